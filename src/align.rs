@@ -0,0 +1,306 @@
+use crate::utils::Anime;
+use std::fmt::{self, Display};
+
+// Emby and Jellyfin split or merge the seasons of a series differently from
+// dandanplay, e.g. a 24 episode anime may be two seasons of 12 episodes, or
+// two 12 episode animes may be one season of 24. The seasons are aligned by
+// the sums of their episode counts.
+
+// an episode of a dandanplay anime
+#[derive(Debug, PartialEq)]
+pub struct Alignment {
+    pub anime_id: u64,
+    pub episode: u64,
+    // what to add to the episode index of the season for any of its episodes,
+    // when the whole season belongs to the anime
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AlignError {
+    NoSeasons,
+    SeasonOutOfRange,
+    // the seasons could be aligned in more than one way
+    Ambiguous,
+    // the seasons have a different number of episodes in total
+    Mismatch,
+    NoMatch,
+}
+
+impl Display for AlignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AlignError::NoSeasons => "no seasons to align",
+            AlignError::SeasonOutOfRange => "season out of range",
+            AlignError::Ambiguous => "need more info, skip",
+            AlignError::Mismatch => "episode counts of the seasons differ",
+            AlignError::NoMatch => "no matching episode with info",
+        })
+    }
+}
+
+impl std::error::Error for AlignError {}
+
+// Finds the anime and episode of episode `ep_index` of season `sn_index` in
+// `seasons`, the (season number, episode count) of the seasons on the server.
+// `animes` are the seasons as dandanplay has them, in order.
+pub fn align(
+    animes: &[Anime],
+    seasons: &[(u64, u64)],
+    sn_index: i64,
+    ep_index: u64,
+) -> Result<Alignment, AlignError> {
+    let &(first_season, _) = seasons.first().ok_or(AlignError::NoSeasons)?;
+    let &(last_season, last_count) = seasons.last().ok_or(AlignError::NoSeasons)?;
+    if animes.is_empty() {
+        return Err(AlignError::NoMatch);
+    }
+    let s = usize::try_from(sn_index)
+        .ok()
+        .filter(|&s| s > 0)
+        .ok_or(AlignError::SeasonOutOfRange)?;
+    let anime = |i: usize| animes.get(i).ok_or(AlignError::SeasonOutOfRange);
+    let count = |i: usize| animes.get(i).map(|anime| anime.episode_count);
+    let whole = |anime: &Anime| Alignment {
+        anime_id: anime.anime_id,
+        episode: ep_index,
+        offset: Some(0),
+    };
+    // the first n seasons have the same number of episodes on both sides
+    let aligned = |n: Option<usize>, m: Option<usize>| {
+        let dan = n.and_then(|n| dan_sum(animes, n));
+        dan.is_some() && dan == m.and_then(|m| em_sum(seasons, m))
+    };
+
+    // 如果季数匹配，则直接返回结果
+    if animes.len() as u64 == last_season || aligned(Some(s), Some(s)) {
+        return Ok(whole(anime(s - 1)?));
+    }
+
+    // the seasons on the server do not start with the first one
+    if first_season != 1 {
+        if s as u64 != last_season || (animes.len() as u64) < last_season {
+            return Err(AlignError::Ambiguous);
+        }
+        if count(s) == Some(last_count) {
+            return Ok(whole(anime(s)?));
+        }
+        if let (Some(previous), Some(next)) = (count(s - 1), count(s)) {
+            if previous + next == last_count {
+                return span(&animes[s - 1..=s], ep_index).ok_or(AlignError::NoMatch);
+            }
+        }
+        return Err(AlignError::Ambiguous);
+    }
+
+    if !aligned(Some(animes.len()), Some(seasons.len())) {
+        return Err(AlignError::Mismatch);
+    }
+
+    // 求解季数被合并的情况
+    // the season is made of the animes from s - 1 + x up to s - 1 + i
+    if animes.len() > seasons.len() {
+        for i in 0..=animes.len() - seasons.len() {
+            if !aligned(Some(s + i), Some(s)) {
+                continue;
+            }
+            for x in 0..=i {
+                if aligned(Some(s - 1 + x), Some(s - 1)) {
+                    return animes
+                        .get(s - 1 + x..s + i)
+                        .and_then(|merged| span(merged, ep_index))
+                        .ok_or(AlignError::SeasonOutOfRange);
+                }
+            }
+        }
+    }
+
+    // 求解季数被拆开的情况
+    // the anime i - 1 starts with the season, or with the one before it
+    if animes.len() < seasons.len() {
+        for i in 1..=animes.len() {
+            let anime = &animes[i - 1];
+            if aligned(Some(i), Some(s)) {
+                if aligned(Some(i - 1), Some(s - 1)) {
+                    return Ok(whole(anime));
+                }
+                if aligned(Some(i - 1), s.checked_sub(2)) {
+                    let offset = seasons[s - 2].1;
+                    return Ok(Alignment {
+                        anime_id: anime.anime_id,
+                        episode: ep_index + offset,
+                        offset: Some(offset as i64),
+                    });
+                }
+            }
+            if aligned(Some(i - 1), Some(s - 1)) && aligned(Some(i), Some(s + 1)) {
+                return Ok(whole(anime));
+            }
+        }
+    }
+
+    Err(AlignError::NoMatch)
+}
+
+// The episode of a season made of several animes. It is only remembered for
+// the season when it is made of a single anime.
+fn span(animes: &[Anime], ep_index: u64) -> Option<Alignment> {
+    let (last, rest) = animes.split_last()?;
+    let mut episode = ep_index;
+    let mut anime = last;
+    for candidate in rest {
+        if episode <= candidate.episode_count {
+            anime = candidate;
+            break;
+        }
+        episode -= candidate.episode_count;
+    }
+    Some(Alignment {
+        anime_id: anime.anime_id,
+        episode,
+        offset: rest.is_empty().then_some(0),
+    })
+}
+
+// 求dandan返回结果中的前n季集数之和
+fn dan_sum(animes: &[Anime], n: usize) -> Option<u64> {
+    Some(
+        animes
+            .get(..n)?
+            .iter()
+            .map(|anime| anime.episode_count)
+            .sum(),
+    )
+}
+
+// 求emby前n季集数和
+fn em_sum(seasons: &[(u64, u64)], n: usize) -> Option<u64> {
+    Some(seasons.get(..n)?.iter().map(|season| season.1).sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // dandanplay episode counts, server episode counts, season, episode
+    type Case<'a, T> = (&'a [u64], &'a [u64], i64, u64, T);
+
+    fn animes(counts: &[u64]) -> Vec<Anime> {
+        counts
+            .iter()
+            .enumerate()
+            .map(|(n, &episode_count)| Anime {
+                anime_id: n as u64 + 1,
+                episode_count,
+                anime_title: String::new(),
+            })
+            .collect()
+    }
+
+    fn seasons(counts: &[u64]) -> Vec<(u64, u64)> {
+        (1..).zip(counts.iter().copied()).collect()
+    }
+
+    #[test]
+    fn aligns_seasons() {
+        // anime, episode, offset
+        let cases: &[Case<(u64, u64, Option<i64>)>] = &[
+            // same seasons
+            (&[12, 12], &[12, 12], 2, 5, (2, 5, Some(0))),
+            (&[12, 13, 12], &[12, 13, 12], 3, 1, (3, 1, Some(0))),
+            // same number of seasons, different counts
+            (&[12, 12], &[13, 11], 1, 13, (1, 13, Some(0))),
+            // two cours merged into one season
+            (&[12, 12], &[24], 1, 5, (1, 5, None)),
+            (&[12, 12], &[24], 1, 13, (2, 1, None)),
+            (&[12, 12], &[24], 1, 24, (2, 12, None)),
+            // three cours merged into one season
+            (&[12, 12, 12], &[36], 1, 30, (3, 6, None)),
+            // the second season merged, the first one not
+            (&[12, 12, 13], &[12, 25], 2, 14, (3, 2, None)),
+            (&[12, 12, 13], &[12, 25], 1, 3, (1, 3, Some(0))),
+            // merged seasons followed by a single one
+            (&[12, 12, 10, 12], &[24, 10, 12], 2, 4, (3, 4, Some(0))),
+            (&[12, 12, 10, 12], &[24, 10, 12], 3, 12, (4, 12, Some(0))),
+            // an anime split into two seasons
+            (&[24], &[12, 12], 1, 5, (1, 5, Some(0))),
+            (&[24], &[12, 12], 2, 5, (1, 17, Some(12))),
+            (&[12, 24], &[12, 12, 12], 3, 1, (2, 13, Some(12))),
+            (&[24, 12], &[12, 12, 12], 3, 4, (2, 4, Some(0))),
+        ];
+        for &(dan, em, sn, ep, (anime_id, episode, offset)) in cases {
+            assert_eq!(
+                align(&animes(dan), &seasons(em), sn, ep),
+                Ok(Alignment {
+                    anime_id,
+                    episode,
+                    offset
+                }),
+                "{:?} {:?} S{}E{}",
+                dan,
+                em,
+                sn,
+                ep
+            );
+        }
+    }
+
+    #[test]
+    fn seasons_not_starting_with_the_first() {
+        let animes = animes(&[12, 12, 13, 11]);
+        // only the third season is on the server, dandanplay has one more
+        // anime before it
+        assert_eq!(
+            align(&animes, &[(3, 11)], 3, 2),
+            Ok(Alignment {
+                anime_id: 4,
+                episode: 2,
+                offset: Some(0)
+            })
+        );
+        assert_eq!(
+            align(&animes, &[(3, 24)], 3, 20),
+            Ok(Alignment {
+                anime_id: 4,
+                episode: 7,
+                offset: None
+            })
+        );
+        assert_eq!(
+            align(&animes, &[(2, 12), (3, 30)], 2, 1),
+            Err(AlignError::Ambiguous)
+        );
+        assert_eq!(align(&animes, &[(3, 30)], 3, 1), Err(AlignError::Ambiguous));
+    }
+
+    #[test]
+    fn rejects_what_does_not_align() {
+        let cases: &[Case<AlignError>] = &[
+            (&[12], &[], 1, 1, AlignError::NoSeasons),
+            (&[], &[12], 1, 1, AlignError::NoMatch),
+            (&[12, 12], &[12, 12, 12], 0, 1, AlignError::SeasonOutOfRange),
+            (
+                &[12, 12],
+                &[12, 12, 12],
+                -1,
+                1,
+                AlignError::SeasonOutOfRange,
+            ),
+            (&[12, 12], &[13, 13, 13], 3, 1, AlignError::Mismatch),
+            (&[12, 12, 12], &[12, 12, 12, 12], 5, 1, AlignError::Mismatch),
+            (&[12, 12, 12], &[18, 18], 2, 1, AlignError::NoMatch),
+            (&[12, 12], &[12, 12], 3, 1, AlignError::SeasonOutOfRange),
+        ];
+        for &(dan, em, sn, ep, ref error) in cases {
+            assert_eq!(
+                align(&animes(dan), &seasons(em), sn, ep).as_ref(),
+                Err(error),
+                "{:?} {:?} S{}E{}",
+                dan,
+                em,
+                sn,
+                ep
+            );
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{
+    dandanplay::{Danmaku, Source},
+    mpv::expand_path,
+    options,
+};
+use anyhow::Result;
+use regex::{Captures, Regex};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+use tracing::info;
+
+static COMMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<d\s+p="([^"]*)"\s*>([^<]*)</d>"#).unwrap());
+static ENTITY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(#[xX][0-9a-fA-F]+|#[0-9]+|[a-z]+);").unwrap());
+
+// font size of a normal bilibili comment
+const NORMAL_SIZE: f64 = 25.;
+
+// <d p="time,mode,size,color,timestamp,pool,user,id">message</d>
+pub fn parse_xml(xml: &str) -> Vec<Danmaku> {
+    COMMENT
+        .captures_iter(xml)
+        .filter_map(|captures| {
+            let p = captures[1].split(',').collect::<Vec<_>>();
+            let [time, mode, size, color, ..] = p[..] else {
+                return None;
+            };
+            // advanced, code and BAS comments are scripts rather than text
+            if matches!(mode, "7" | "8" | "9") {
+                return None;
+            }
+            Some(Danmaku::new(
+                &unescape(&captures[2]),
+                time.parse().ok().filter(|time: &f64| time.is_finite())?,
+                mode.into(),
+                size.parse().ok().filter(|size: &f64| size.is_finite())? / NORMAL_SIZE,
+                color.parse().ok()?,
+                Source::Bilibili,
+                p.get(6).copied().unwrap_or_default(),
+            ))
+        })
+        .collect()
+}
+
+fn unescape(text: &str) -> String {
+    ENTITY
+        .replace_all(text, |captures: &Captures| {
+            let entity = &captures[1];
+            let c = match entity {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .map(|hex| u32::from_str_radix(hex, 16))
+                    .or_else(|| entity.strip_prefix('#').map(str::parse))
+                    .and_then(|code| code.ok())
+                    .and_then(char::from_u32),
+            };
+            c.map_or_else(|| captures[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+// Looks for the sidecar file described by `local_danmaku_path` next to the video.
+pub fn load_sidecar(video: &str) -> Result<Option<Vec<Danmaku>>> {
+    let video = Path::new(video);
+    let name = video
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let pattern = options::get().local_danmaku_path.replace("{name}", name);
+    let path = if pattern.starts_with('~') {
+        PathBuf::from(expand_path(&pattern)?)
+    } else {
+        video.parent().unwrap_or(Path::new("")).join(pattern)
+    };
+
+    let xml = match fs::read_to_string(&path) {
+        Ok(xml) => xml,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    info!("Loading local danmaku from {}", path.display());
+
+    Ok(Some(parse_xml(&xml)))
+}
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result, anyhow};
+use danmaku::{
+    CLIENT_NAME, bilibili,
+    comments::Comments,
+    dandanplay::{cached_danmaku, parse_comments, prepare},
+    options,
+    render::{Params, to_ass},
+};
+use std::{env, fs, path::Path, process::ExitCode};
+use tokio::runtime::Builder;
+
+const USAGE: &str = "\
+usage: danmaku2ass [options] <input>
+
+<input> is a dandanplay comment JSON file, a bilibili XML file, or the id of
+an episode whose comments are cached in ~~/files/danmaku.
+
+options:
+  -o, --output <file>   where to write the subtitles, <input>.ass by default
+  -s, --size <WxH>      aspect ratio of the video, 1920x1080 by default
+  -c, --client <name>   read ~~/script-opts/<name>.conf, danmaku by default";
+
+struct Args {
+    input: String,
+    output: Option<String>,
+    size: (f64, f64),
+    client: String,
+}
+
+// Ok(None) when only the usage was asked for.
+fn parse_args() -> Result<Option<Args>> {
+    let mut input = None;
+    let mut output = None;
+    let mut size = (1920., 1080.);
+    let mut client = "danmaku".to_string();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value()?),
+            "-s" | "--size" => {
+                let value = value()?;
+                size = value
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .filter(|&(w, h): &(f64, f64)| w > 0. && h > 0.)
+                    .ok_or_else(|| anyhow!("invalid size {}", value))?;
+            }
+            "-c" | "--client" => client = value()?,
+            "-h" | "--help" => return Ok(None),
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg),
+            _ => return Err(anyhow!("unexpected argument {}", arg)),
+        }
+    }
+
+    Ok(Some(Args {
+        input: input.ok_or_else(|| anyhow!("no input"))?,
+        output,
+        size,
+        client,
+    }))
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    match runtime.block_on(run(args)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("danmaku2ass: {:#}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    // the conf file and the blocklists are shared with the script
+    CLIENT_NAME.get_or_init(|| args.client.clone());
+    let (conf, filter) = options::read_options()?.unwrap_or_default();
+    options::set(conf.clone());
+
+    let mut danmaku = if let Ok(episode_id) = args.input.parse::<usize>() {
+        cached_danmaku(episode_id)
+            .await?
+            .ok_or_else(|| anyhow!("episode {} is not cached", episode_id))?
+    } else {
+        let contents = fs::read_to_string(&args.input)
+            .with_context(|| format!("failed to read {}", args.input))?;
+        if contents.trim_start().starts_with('<') {
+            bilibili::parse_xml(&contents)
+        } else {
+            parse_comments(&contents)?
+        }
+    };
+    prepare(&mut danmaku, &filter).await;
+
+    let mut comments = Comments::new(danmaku);
+    let params = Params {
+        delay: 0.,
+        speed: 1.,
+        pause: false,
+        osd_width: args.size.0,
+        osd_height: args.size.1,
+        timed: true,
+    };
+    let ass = to_ass(&mut comments, params, &conf);
+    let output = args.output.unwrap_or_else(|| {
+        Path::new(&args.input)
+            .with_extension("ass")
+            .to_string_lossy()
+            .into_owned()
+    });
+    fs::write(&output, ass).with_context(|| format!("failed to write {}", output))?;
+    println!("Wrote {} comments to {}", comments.len(), output);
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::{log::log_error, mpv::expand_path};
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::Path,
+    sync::{Mutex, OnceLock, RwLock, RwLockReadGuard},
+};
+
+// A set of strings kept in ~~/files/danmaku, one per line, so that it can
+// also be edited by hand.
+pub struct List {
+    path: &'static str,
+    // read from the file the first time the list is used
+    entries: OnceLock<RwLock<HashSet<String>>>,
+    // the entry added last, to undo it
+    last: Mutex<Option<String>>,
+}
+
+pub static USERS: List = List::new("~~/files/danmaku/blocked_users");
+pub static KEYWORDS: List = List::new("~~/files/danmaku/blocked_keywords");
+
+impl List {
+    const fn new(path: &'static str) -> Self {
+        Self {
+            path,
+            entries: OnceLock::new(),
+            last: Mutex::new(None),
+        }
+    }
+
+    fn entries(&self) -> &RwLock<HashSet<String>> {
+        self.entries.get_or_init(|| {
+            RwLock::new(self.load().unwrap_or_else(|error| {
+                log_error(&error.context(format!("failed to read {}", self.path)));
+                HashSet::new()
+            }))
+        })
+    }
+
+    fn load(&self) -> Result<HashSet<String>> {
+        match fs::read_to_string(expand_path(self.path)?) {
+            Ok(contents) => Ok(contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn save(&self, entries: &HashSet<String>) -> Result<()> {
+        let path = expand_path(self.path)?;
+        if let Some(parent) = Path::new(&path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut entries = entries.iter().map(String::as_str).collect::<Vec<_>>();
+        entries.sort_unstable();
+        fs::write(path, entries.join("\n") + "\n")?;
+
+        Ok(())
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, HashSet<String>> {
+        self.entries().read().unwrap()
+    }
+
+    // Returns false when the entry was already there.
+    pub fn add(&self, entry: &str) -> Result<bool> {
+        let mut entries = self.entries().write().unwrap();
+        if !entries.insert(entry.to_string()) {
+            return Ok(false);
+        }
+        self.save(&entries)?;
+        *self.last.lock().unwrap() = Some(entry.to_string());
+
+        Ok(true)
+    }
+
+    // Returns false when there was no such entry.
+    pub fn remove(&self, entry: &str) -> Result<bool> {
+        let mut entries = self.entries().write().unwrap();
+        if !entries.remove(entry) {
+            return Ok(false);
+        }
+        self.save(&entries)?;
+
+        Ok(true)
+    }
+
+    pub fn last(&self) -> Option<String> {
+        self.last.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{
+    CLIENT_NAME,
+    dandanplay::{Danmaku, Source},
+    menu,
+    mpv::{command, osd_message},
+};
+
+const SECTION: &str = "danmaku-click";
+
+// Binds the left mouse button to the `danmaku-click` script-message. The
+// binding is forced, so it takes the button from mpv while enabled.
+pub fn bind(enabled: bool) {
+    if enabled {
+        let client = CLIENT_NAME.get().map_or("", |name| name.as_str());
+        let contents = format!("MBTN_LEFT script-message-to {client} danmaku-click");
+        command(&["define-section", SECTION, &contents, "force"]);
+        command(&["enable-section", SECTION]);
+    } else {
+        command(&["disable-section", SECTION]);
+    }
+}
+
+// what is needed to act on a clicked comment after the lock is released
+pub struct Target {
+    message: String,
+    user: String,
+    time: f64,
+    source: Source,
+}
+
+impl From<&Danmaku> for Target {
+    fn from(comment: &Danmaku) -> Self {
+        Self {
+            message: comment.message.clone(),
+            user: comment.user.clone(),
+            time: comment.time,
+            source: comment.source,
+        }
+    }
+}
+
+// Lets the user pick what to do with the clicked comment. Blocking goes
+// through the script-messages so that the loaded comments are filtered again.
+pub async fn act(target: Target) {
+    let client = CLIENT_NAME.get().map_or("", |name| name.as_str());
+    let mut items = vec![
+        "Copy text".to_string(),
+        format!("Block keyword: {}", target.message),
+    ];
+    if !target.user.is_empty() {
+        items.push(format!("Block user: {}", target.user));
+    }
+    items.push(format!(
+        "Sent at {}:{:02} from {:?}",
+        (target.time / 60.) as u64,
+        (target.time % 60.) as u64,
+        target.source
+    ));
+
+    // closing the menu is not an error here
+    let Ok(choice) = menu::choose(&format!("Danmaku: {}", target.message), items).await else {
+        return;
+    };
+    match choice {
+        0 => {
+            command(&["set", "clipboard/text", &target.message]);
+            osd_message("Danmaku: copied to clipboard");
+        }
+        1 => command(&[
+            "script-message-to",
+            client,
+            "danmaku-block-keyword",
+            &target.message,
+        ]),
+        2 if !target.user.is_empty() => command(&[
+            "script-message-to",
+            client,
+            "danmaku-block-user",
+            &target.user,
+        ]),
+        _ => osd_message(&format!(
+            "Danmaku: sent at {:.1}s from {:?}, user {}",
+            target.time, target.source, target.user
+        )),
+    }
+}
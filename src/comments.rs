@@ -0,0 +1,103 @@
+use crate::dandanplay::{Danmaku, Status};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static REVISION: AtomicU64 = AtomicU64::new(0);
+
+// where a comment was drawn, to find it again when it is clicked
+pub struct Shown {
+    // position in `danmaku`
+    pub index: usize,
+    // top left corner at playback position `pos`
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    // leftwards, in pixels per second of video time
+    pub velocity: f64,
+    pub pos: f64,
+    // playback position at which the comment disappears
+    pub until: f64,
+}
+
+pub struct Comments {
+    // sorted by time
+    pub danmaku: Vec<Danmaku>,
+    // positions of the comments that are not blocked, in time order
+    pub index: Vec<usize>,
+    // first entry of `index` that may still be on screen
+    pub cursor: usize,
+    // comments drawn by the last render, and the size of the frame they were drawn in
+    pub shown: Vec<Shown>,
+    pub frame: (f64, f64),
+    // changes whenever the comments or which of them are blocked change
+    pub revision: u64,
+}
+
+impl Comments {
+    pub fn new(danmaku: Vec<Danmaku>) -> Self {
+        let mut comments = Comments {
+            danmaku,
+            index: Vec::new(),
+            cursor: 0,
+            shown: Vec::new(),
+            frame: (1920., 1080.),
+            revision: 0,
+        };
+        comments.reindex();
+        comments
+    }
+
+    pub fn reindex(&mut self) {
+        self.index = self
+            .danmaku
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| !c.blocked)
+            .map(|(i, _)| i)
+            .collect();
+        self.cursor = 0;
+        self.revision = REVISION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// The latest comment laid out on screen by `time`, the playback
+    /// position without the delay.
+    pub fn latest_shown(&self, time: f64) -> Option<&Danmaku> {
+        self.index[self.cursor..]
+            .iter()
+            .map(|&i| &self.danmaku[i])
+            .take_while(|comment| comment.time <= time)
+            .filter(|comment| matches!(comment.status, Status::Status(_)))
+            .last()
+    }
+
+    /// The comment drawn at `(x, y)` of the frame at playback position `pos`.
+    pub fn hit(&self, x: f64, y: f64, pos: f64) -> Option<&Danmaku> {
+        self.shown
+            .iter()
+            .rev()
+            .find(|shown| {
+                let left = shown.x - shown.velocity * (pos - shown.pos);
+                pos <= shown.until
+                    && (left..left + shown.width).contains(&x)
+                    && (shown.y..shown.y + shown.height).contains(&y)
+            })
+            .map(|shown| &self.danmaku[shown.index])
+    }
+
+    /// Forgets every layout and moves the cursor to the first comment
+    /// timed at or after `from`.
+    pub fn reset(&mut self, from: f64) {
+        for comment in &mut self.danmaku {
+            comment.status = Status::Uninitialized;
+        }
+        self.cursor = self.index.partition_point(|&i| self.danmaku[i].time < from);
+    }
+}
@@ -1,15 +1,52 @@
 use crate::utils::{AnimeOffset, CLIENT, Linkage};
 use crate::{
+    align::align,
     emby::{EpInfo, get_episode_info, get_series_info},
-    mpv::osd_message,
+    log::log_error,
+    menu, merge,
+    mpv::{get_property_f64, osd_message},
     options::{self, Filter},
+    store,
 };
 use anyhow::{Ok, Result, anyhow};
+use reqwest::{Method, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{hint, sync::Arc};
+use std::{
+    borrow::Cow,
+    hint,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::{error, info};
-use unicode_segmentation::UnicodeSegmentation;
+
+// Builds a request to `path` of the dandanplay api, signed with the app
+// credentials when they are configured:
+// X-Signature = base64(sha256(app_id + timestamp + path + app_secret))
+fn api(method: Method, path: &str) -> RequestBuilder {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use sha2::{Digest, Sha256};
+
+    let options = options::get();
+    let request = CLIENT.request(method, format!("{}{}", options.api_base, path));
+    if options.app_id.is_empty() || options.app_secret.is_empty() {
+        return request;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let mut hasher = Sha256::new();
+    hasher.update(options.app_id.as_bytes());
+    hasher.update(timestamp.to_string());
+    hasher.update(path);
+    hasher.update(options.app_secret.as_bytes());
+
+    request
+        .header("X-AppId", &*options.app_id)
+        .header("X-Timestamp", timestamp)
+        .header("X-Signature", STANDARD.encode(hasher.finalize()))
+}
 
 pub struct StatusInner {
     pub x: f64,
@@ -33,18 +70,89 @@ impl Status {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Scroll,
+    Top,
+    Bottom,
+}
+
+impl From<&str> for Mode {
+    fn from(value: &str) -> Self {
+        match value {
+            "4" => Mode::Bottom,
+            "5" => Mode::Top,
+            _ => Mode::Scroll,
+        }
+    }
+}
+
 pub struct Danmaku {
     pub message: String,
-    pub count: usize,
+    // in units of the font size
+    pub width: f64,
     pub time: f64,
+    pub mode: Mode,
+    // font size relative to `font_size`
+    pub scale: f64,
     pub r: u8,
     pub g: u8,
     pub b: u8,
     pub source: Source,
+    pub user: String,
+    // number of duplicates this comment stands for
+    pub merged: usize,
+    // by the filter, the density limit, or as a duplicate of an earlier
+    // comment
     pub blocked: bool,
     pub status: Status,
 }
 
+impl Danmaku {
+    pub fn new(
+        message: &str,
+        time: f64,
+        mode: Mode,
+        scale: f64,
+        color: u32,
+        source: Source,
+        user: &str,
+    ) -> Self {
+        Danmaku {
+            message: message.replace('\n', "\\N"),
+            // measured when the filter is applied
+            width: 0.,
+            time,
+            mode,
+            scale,
+            r: (color >> 16) as u8,
+            g: (color >> 8) as u8,
+            b: color as u8,
+            source,
+            user: user.to_string(),
+            merged: 1,
+            blocked: false,
+            status: Status::Uninitialized,
+        }
+    }
+
+    // Font size relative to `font_size` as it is shown, larger when the
+    // comment stands for duplicates.
+    pub fn size(&self) -> f64 {
+        self.scale * merge::growth(self.merged)
+    }
+
+    // The message as it is shown, suffixed with "×N" when it stands for
+    // duplicates.
+    pub fn text(&self) -> Cow<'_, str> {
+        if self.merged > 1 {
+            Cow::Owned(format!("{} ×{}", self.message, self.merged))
+        } else {
+            Cow::Borrowed(&self.message)
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct MatchResponse {
     #[serde(rename = "isMatched")]
@@ -56,74 +164,147 @@ struct MatchResponse {
 struct Match {
     #[serde(rename = "episodeId")]
     episode_id: usize,
+    #[serde(default, rename = "animeTitle")]
+    anime_title: String,
+    #[serde(default, rename = "episodeTitle")]
+    episode_title: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 struct Comment {
     p: String,
     m: String,
 }
 
-#[derive(Deserialize, Serialize)]
+impl TryFrom<Comment> for Danmaku {
+    type Error = anyhow::Error;
+
+    // `p` is "time,mode,color,user"
+    fn try_from(comment: Comment) -> Result<Self> {
+        let invalid = || anyhow!("invalid comment attributes {:?}", comment.p);
+        let mut p = comment.p.splitn(4, ',');
+        let time = p
+            .next()
+            .and_then(|time| time.parse::<f64>().ok())
+            .filter(|time| time.is_finite())
+            .ok_or_else(invalid)?;
+        let mode = p.next().ok_or_else(invalid)?.into();
+        let color = p
+            .next()
+            .and_then(|color| color.parse::<u32>().ok())
+            .ok_or_else(invalid)?;
+        let user = p.next().ok_or_else(invalid)?;
+        let (source, user) = if user.chars().all(char::is_numeric) {
+            (Source::Dandan, user)
+        } else {
+            user.strip_prefix('[')
+                .and_then(|user| user.split_once(']'))
+                .map(|(source, user)| (source.into(), user))
+                .unwrap_or((Source::Unknown, user))
+        };
+        Ok(Danmaku::new(
+            &comment.m, time, mode, 1., color, source, user,
+        ))
+    }
+}
+
+// Comments from the api or the cache, without any that are malformed.
+fn valid(comments: Vec<Comment>) -> impl Iterator<Item = Danmaku> {
+    comments
+        .into_iter()
+        .filter_map(|comment| Danmaku::try_from(comment).ok())
+}
+
+#[derive(Deserialize)]
 struct CommentResponse {
     comments: Vec<Comment>,
 }
 
 impl CommentResponse {
     async fn get(episode_id: usize) -> Result<Self> {
-        Ok(CLIENT
-            .get(format!(
-                "https://api.dandanplay.net/api/v2/comment/{}?withRelated=true",
-                episode_id
-            ))
+        Ok(api(Method::GET, &format!("/api/v2/comment/{}", episode_id))
+            .query(&[("withRelated", "true")])
             .send()
             .await?
             .json::<CommentResponse>()
             .await?)
     }
+}
 
-    async fn save(&self, episode_id: usize) -> Result<()> {
-        use crate::mpv::expand_path;
-        use std::path::Path;
-        use tokio::io::AsyncWriteExt;
-
-        let encoded: Vec<u8> = bincode::serde::encode_to_vec(self, bincode::config::legacy())?;
-        let path_str = expand_path(&format!("~~/files/danmaku/{}", episode_id))?;
-        let path = Path::new(&path_str);
+// comments of an episode as kept in ~~/files/danmaku/<episode_id>
+#[derive(Deserialize, Serialize)]
+struct CachedComments {
+    fetched: SystemTime,
+    // the last refresh brought no new comments
+    stable: bool,
+    comments: Vec<Comment>,
+}
 
-        if !path.parent().expect("no parent dir").exists() {
-            std::fs::create_dir_all(path.parent().expect("no parent dir"))?;
-        }
+impl CachedComments {
+    fn is_stale(&self) -> bool {
+        let options = options::get();
+        let ttl = if self.stable {
+            options.cache_ttl_stable
+        } else {
+            options.cache_ttl
+        };
+        SystemTime::now()
+            .duration_since(self.fetched)
+            .is_ok_and(|age| age.as_secs_f64() > ttl * 86400.)
+    }
 
-        let mut file = tokio::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(path)
-            .await?;
+    async fn save(&self, episode_id: usize) -> Result<()> {
+        store::save(&format!("~~/files/danmaku/{}", episode_id), self).await
+    }
 
-        file.write_all(&encoded).await?;
+    // Ok(None) when the episode has not been cached yet.
+    async fn load(episode_id: usize) -> Result<Option<Self>> {
+        let Some(contents) = store::read(&format!("~~/files/danmaku/{}", episode_id)).await? else {
+            return Ok(None);
+        };
 
-        Ok(())
+        store::decode(&contents).map(Some)
     }
+}
 
-    async fn load(episode_id: usize) -> Result<Self> {
-        use super::mpv::expand_path;
-        use std::path::Path;
-        use tokio::fs::File;
-        use tokio::io::AsyncReadExt;
-
-        let path_str = expand_path(&format!("~~/files/danmaku/{}", episode_id))?;
-        let path = Path::new(&path_str);
+// 1: the first container
+// 2: the matching database changed its layout, the cache did not
+impl store::Stored for CachedComments {
+    const VERSION: u16 = 2;
+
+    fn migrate(version: u16, payload: &[u8]) -> Result<Self> {
+        match version {
+            1 => store::decode_legacy(payload),
+            // the fetch time was recorded before the container existed, but
+            // not by the first caches, which are refreshed on the next load
+            store::UNVERSIONED => store::decode_legacy(payload).or_else(|_| {
+                Ok(CachedComments {
+                    fetched: SystemTime::UNIX_EPOCH,
+                    stable: false,
+                    comments: store::decode_legacy::<CommentResponse>(payload)?.comments,
+                })
+            }),
+            _ => Err(anyhow!("unsupported cache version {}", version)),
+        }
+    }
+}
 
-        let mut file = File::open(path).await?;
-        let mut contents = vec![];
-        file.read_to_end(&mut contents).await?;
+// Comments in the format of the comment API, e.g. saved from a browser.
+pub fn parse_comments(json: &str) -> Result<Vec<Danmaku>> {
+    let response = serde_json::from_str::<CommentResponse>(json)?;
+    response
+        .comments
+        .into_iter()
+        .map(TryInto::try_into)
+        .collect()
+}
 
-        let comments: CommentResponse =
-            bincode::serde::decode_from_slice(&contents, bincode::config::legacy())?.0;
-        Ok(comments)
-    }
+// Comments of an episode from the cache alone, Ok(None) when it has not been
+// cached yet.
+pub async fn cached_danmaku(episode_id: usize) -> Result<Option<Vec<Danmaku>>> {
+    Ok(CachedComments::load(episode_id)
+        .await?
+        .map(|cached| valid(cached.comments).collect()))
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
@@ -153,18 +334,256 @@ impl From<&str> for Source {
     }
 }
 
-pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>> {
+fn get_local_danmaku(path: &str) -> Option<Vec<Danmaku>> {
+    use crate::{bilibili, options::LocalDanmaku, utils::is_http_link};
+
+    match options::get().local_danmaku {
+        LocalDanmaku::No => None,
+        _ if is_http_link(path) => None,
+        _ => bilibili::load_sidecar(path).unwrap_or_else(|error| {
+            error!("Failed to load local danmaku: {}", error);
+            None
+        }),
+    }
+}
+
+// The second value is the episode whose cached comments are due for a
+// refresh with `refresh_danmaku`.
+pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<(Vec<Danmaku>, Option<usize>)> {
+    use crate::options::LocalDanmaku;
+    use std::result::Result::Ok;
+
+    let (mut danmaku, stale) = match get_local_danmaku(path) {
+        Some(local) if options::get().local_danmaku == LocalDanmaku::Replace => {
+            info!("Using {} local danmaku comments", local.len());
+            (local, None)
+        }
+        Some(mut local) => {
+            info!("Merging {} local danmaku comments", local.len());
+            match get_remote_danmaku(path).await {
+                Ok((remote, stale)) => {
+                    local.extend(remote);
+                    (local, stale)
+                }
+                Err(error) => {
+                    error!("Failed to get remote danmaku: {}", error);
+                    (local, None)
+                }
+            }
+        }
+        None => get_remote_danmaku(path).await?,
+    };
+
+    prepare(&mut danmaku, &filter).await;
+
+    Ok((danmaku, stale))
+}
+
+// Comments fetched again for the cache, which is only brought up to date
+// with `save` once they are shown. A refresh cut short leaves the cache stale
+// so that it is tried again.
+pub struct Refreshed {
+    episode_id: usize,
+    cached: CachedComments,
+}
+
+impl Refreshed {
+    pub async fn save(&self) -> Result<()> {
+        self.cached.save(self.episode_id).await
+    }
+}
+
+// Fetches the comments of `episode_id` again, returns the danmaku of the
+// playing file when new comments came in.
+pub async fn refresh_danmaku(
+    path: &str,
+    episode_id: usize,
+    filter: Arc<Filter>,
+) -> Result<(Option<Vec<Danmaku>>, Refreshed)> {
+    let cached = refresh_comments(episode_id).await?;
+    let danmaku = if cached.stable {
+        None
+    } else {
+        let mut danmaku = get_local_danmaku(path).unwrap_or_default();
+        danmaku.extend(valid(cached.comments.clone()));
+        prepare(&mut danmaku, &filter).await;
+        Some(danmaku)
+    };
+
+    Ok((danmaku, Refreshed { episode_id, cached }))
+}
+
+// Lets the user pick an anime and episode matching `keyword`, and links the
+// choice to the playing file.
+pub async fn search_danmaku(
+    path: &str,
+    keyword: &str,
+    filter: Arc<Filter>,
+) -> Result<(Vec<Danmaku>, Option<usize>)> {
+    use crate::utils::SearchRes;
+
+    let res = api(Method::GET, "/api/v2/search/anime")
+        .query(&[("keyword", keyword)])
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        error!("Failed to search anime, Status: {:?}", res.status());
+        return Err(anyhow!("failed to search anime, try again later"));
+    }
+    let animes = res.json::<SearchRes>().await?.animes;
+    if animes.is_empty() {
+        return Err(anyhow!("no anime found for {}", keyword));
+    }
+
+    let items = animes
+        .iter()
+        .map(|anime| format!("{} ({} episodes)", anime.anime_title, anime.episode_count))
+        .collect();
+    let anime = &animes[menu::choose(&format!("Danmaku: results for {}", keyword), items).await?];
+
+    let res = api(Method::GET, &format!("/api/v2/bangumi/{}", anime.anime_id))
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        error!("Failed to fetch episodes, Status: {:?}", res.status());
+        return Err(anyhow!("failed to fetch episodes, try again later"));
+    }
+    let episodes = res.json::<Bangumi>().await?.bangumi.episodes;
+    if episodes.is_empty() {
+        return Err(anyhow!("no episodes found for {}", anime.anime_title));
+    }
+
+    let items = episodes
+        .iter()
+        .map(|episode| episode.episode_title.clone())
+        .collect();
+    let episode_id = episodes[menu::choose(&anime.anime_title, items).await?].episode_id;
+    info!("Selected episode id: {}", episode_id);
+
+    if let Err(error) = link_episode(path, episode_id).await {
+        error!("Failed to remember selected episode: {}", error);
+    }
+
+    let (comments, stale) = get_comments(episode_id).await?;
+    let mut danmaku: Vec<_> = valid(comments).collect();
+    prepare(&mut danmaku, &filter).await;
+
+    Ok((danmaku, stale.then_some(episode_id)))
+}
+
+async fn link_episode(path: &str, episode_id: usize) -> Result<()> {
+    use crate::utils::{Linkage, is_http_link};
+
+    let mut linkage = Linkage::load().await;
+    if is_http_link(path) {
+        let ep_info = get_episode_info(path).await?;
+        if !ep_info.status {
+            return Ok(());
+        }
+        if ep_info.r#type != "movie" {
+            let anime_id = episode_id / 10000;
+            let offset =
+                (episode_id - (anime_id * 10000)) as i64 - ep_info.item_info.ep_index as i64;
+            linkage.insert_seasons(
+                &ep_info.host,
+                &ep_info.item_info.se_id,
+                AnimeOffset { anime_id, offset },
+            );
+        }
+        linkage.insert_items(&ep_info.host, &ep_info.item_info.item_id, episode_id);
+    } else {
+        linkage.link_file(path, episode_id);
+    }
+    linkage.save_as_bincode().await
+}
+
+// Drops the remembered episode of `path`. Returns false when there was none.
+pub async fn forget_match(path: &str) -> Result<bool> {
+    use crate::{
+        emby::extract_params,
+        utils::{Linkage, is_http_link},
+    };
+    use std::result::Result::Ok;
+
+    let mut linkage = Linkage::load().await;
+    let forgotten = if is_http_link(path) {
+        let params = extract_params(path)?;
+        let mut forgotten = linkage.remove_items(&params.host, &params.item_id);
+        // the season offset would match the episode the same way again
+        match get_episode_info(path).await {
+            Ok(ep_info) if ep_info.status => {
+                forgotten |= linkage.remove_seasons(&ep_info.host, &ep_info.item_info.se_id);
+            }
+            Ok(_) => (),
+            Err(error) => error!("Failed to get episode info: {}", error),
+        }
+        forgotten
+    } else {
+        linkage.forget_file(path)
+    };
+    if forgotten {
+        linkage.save_as_bincode().await?;
+    }
+
+    Ok(forgotten)
+}
+
+// Sorts freshly parsed comments and applies the filter.
+pub async fn prepare(danmaku: &mut [Danmaku], filter: &Filter) {
+    danmaku.sort_by(|a, b| a.time.total_cmp(&b.time));
+    filter.apply(danmaku).await;
+}
+
+async fn get_remote_danmaku(path: &str) -> Result<(Vec<Danmaku>, Option<usize>)> {
     use crate::utils::Linkage;
-    use crate::utils::{get_localfile_hash, get_localfile_name, get_stream_hash, is_http_link};
+    use crate::utils::{get_localfile_name, is_http_link};
     use std::result::Result::Ok;
 
     let episode_id = if !is_http_link(path) {
         info!("Now playing non HTTP(s) files");
 
-        let hash = get_localfile_hash(path)?;
-        let file_name = get_localfile_name(path);
-
-        get_episode_id_by_hash(&hash, &file_name).await?
+        let mut linkage = Linkage::load().await;
+        // a file matched on its own takes precedence over its directory
+        let stamped = linkage.stamped_hash(path).is_some();
+        let hash = linkage.file_hash(path);
+        let remembered = hash.as_ref().ok().and_then(|hash| linkage.get_files(hash));
+        if let Some(episode_id) = remembered {
+            info!("Using remembered episode id: {}", episode_id);
+            if !stamped {
+                linkage.save_as_bincode().await?;
+            }
+            episode_id
+        } else if let Some(episode_id) = linkage.get_directories(path) {
+            info!(
+                "Using episode id inferred from the directory: {}",
+                episode_id
+            );
+            if !stamped {
+                linkage.save_as_bincode().await?;
+            }
+            episode_id
+        } else {
+            let file_name = get_localfile_name(path);
+            let hashed = match hash {
+                Ok(hash) => get_episode_id_by_hash(&hash, &file_name, &mut linkage).await,
+                Err(error) => Err(error),
+            };
+            let episode_id = match hashed {
+                Ok(episode_id) => episode_id,
+                Err(error) => {
+                    info!("Failed to match by hash: {}", error);
+                    osd_message("Danmaku: matching by file name");
+                    let episode_id = get_episode_id_by_name(path).await?;
+                    if let Some(hash) = linkage.stamped_hash(path) {
+                        linkage.insert_files(&hash, episode_id);
+                    }
+                    episode_id
+                }
+            };
+            linkage.insert_directories(path, episode_id);
+            linkage.save_as_bincode().await?;
+            episode_id
+        }
     } else {
         let ep_info = get_episode_info(path).await?;
 
@@ -173,10 +592,7 @@ pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>
 
         let file_name = ep_info.get_name();
         if ep_info.status {
-            let mut linkage = match Linkage::load_from_bincode().await {
-                Ok(s) => s,
-                Err(_) => Linkage::new(),
-            };
+            let mut linkage = Linkage::load().await;
 
             let mut episode_id = 0usize;
 
@@ -188,9 +604,7 @@ pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>
                     Err(_) => {
                         osd_message("trying matching with video hash");
                         episode_id =
-                            match get_episode_id_by_hash(&get_stream_hash(path).await?, &file_name)
-                                .await
-                            {
+                            match get_episode_id_by_stream(path, &file_name, &mut linkage).await {
                                 Ok(id) => {
                                     let anime_id = id / 10000;
                                     let offset = (id - (anime_id * 10000)) as i64
@@ -220,27 +634,24 @@ pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>
                         Ok(p) => episode_id = p,
                         Err(_) => {
                             osd_message("trying matching with video hash");
-                            episode_id = match get_episode_id_by_hash(
-                                &get_stream_hash(path).await?,
-                                &file_name,
-                            )
-                            .await
-                            {
-                                Ok(id) => {
-                                    let anime_id = id / 10000;
-                                    let offset = (id - (anime_id * 10000)) as i64
-                                        - ep_info.item_info.ep_index as i64;
-
-                                    let ani_offset = AnimeOffset { anime_id, offset };
-                                    linkage.insert_seasons(
-                                        &ep_info.host,
-                                        &ep_info.item_info.se_id,
-                                        ani_offset,
-                                    );
-                                    id
+                            episode_id =
+                                match get_episode_id_by_stream(path, &file_name, &mut linkage).await
+                                {
+                                    Ok(id) => {
+                                        let anime_id = id / 10000;
+                                        let offset = (id - (anime_id * 10000)) as i64
+                                            - ep_info.item_info.ep_index as i64;
+
+                                        let ani_offset = AnimeOffset { anime_id, offset };
+                                        linkage.insert_seasons(
+                                            &ep_info.host,
+                                            &ep_info.item_info.se_id,
+                                            ani_offset,
+                                        );
+                                        id
+                                    }
+                                    Err(e) => return Err(e),
                                 }
-                                Err(e) => return Err(e),
-                            }
                         }
                     }
 
@@ -258,67 +669,90 @@ pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>
             episode_id
         } else {
             osd_message("trying matching with video hash");
-            get_episode_id_by_hash(&get_stream_hash(path).await?, &file_name).await?
+            let mut linkage = Linkage::load().await;
+            let episode_id = get_episode_id_by_stream(path, &file_name, &mut linkage).await?;
+            linkage.save_as_bincode().await?;
+            episode_id
         }
     };
 
-    let danmaku = match CommentResponse::load(episode_id).await {
-        Ok(res) => res.comments,
-        Err(_) => {
-            let comres = CommentResponse::get(episode_id).await?;
-            comres.save(episode_id).await?;
-            comres.comments
-        }
-    };
+    let (comments, stale) = get_comments(episode_id).await?;
 
-    let sources_rt = filter.sources_rt.lock().await;
-    let mut danmaku = danmaku
-        .into_iter()
-        .filter(|comment| filter.keywords.iter().all(|pat| !comment.m.contains(pat)))
-        .map(|comment| {
-            let mut p = comment.p.splitn(4, ',');
-            let time = p.next().unwrap().parse().unwrap();
-            _ = p.next().unwrap();
-            let color = p.next().unwrap().parse::<u32>().unwrap();
-            let user = p.next().unwrap();
-            let source = if user.chars().all(char::is_numeric) {
-                Source::Dandan
-            } else {
-                user.strip_prefix('[')
-                    .and_then(|user| user.split_once(']').map(|(source, _)| source.into()))
-                    .unwrap_or(Source::Unknown)
+    Ok((valid(comments).collect(), stale.then_some(episode_id)))
+}
+
+// Serves the cached comments of the episode when there are any, the second
+// value tells whether they are old enough to be refreshed.
+async fn get_comments(episode_id: usize) -> Result<(Vec<Comment>, bool)> {
+    use std::result::Result::Ok;
+
+    let cached = CachedComments::load(episode_id)
+        .await
+        .unwrap_or_else(|error| {
+            log_error(&anyhow!(
+                "failed to read cached comments of episode {}: {}",
+                episode_id,
+                error
+            ));
+            None
+        });
+    match cached {
+        Some(cached) => {
+            let stale = cached.is_stale();
+            Ok((cached.comments, stale))
+        }
+        None => {
+            let cached = CachedComments {
+                fetched: SystemTime::now(),
+                stable: false,
+                comments: CommentResponse::get(episode_id).await?.comments,
             };
-            Danmaku {
-                message: comment.m.replace('\n', "\\N"),
-                count: comment.m.graphemes(true).count(),
-                time,
-                r: (color / (256 * 256)).try_into().unwrap(),
-                g: (color % (256 * 256) / 256).try_into().unwrap(),
-                b: (color % 256).try_into().unwrap(),
-                source,
-                blocked: sources_rt
-                    .as_ref()
-                    .map(|s| s.contains(&source))
-                    .unwrap_or_else(|| filter.sources.contains(&source)),
-                status: Status::Uninitialized,
-            }
-        })
-        .collect::<Vec<_>>();
+            cached.save(episode_id).await?;
+            Ok((cached.comments, false))
+        }
+    }
+}
 
-    danmaku.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+// Fetches the comments of the episode again, they are `stable` when there are
+// as many as cached.
+async fn refresh_comments(episode_id: usize) -> Result<CachedComments> {
+    let old = CachedComments::load(episode_id)
+        .await
+        .ok()
+        .flatten()
+        .map_or(0, |cached| cached.comments.len());
+    let comments = CommentResponse::get(episode_id).await?.comments;
+    info!(
+        "Refreshed comments of episode {}: {} -> {}",
+        episode_id,
+        old,
+        comments.len()
+    );
 
-    Ok(danmaku)
+    Ok(CachedComments {
+        fetched: SystemTime::now(),
+        stable: comments.len() == old,
+        comments,
+    })
 }
 
-async fn get_episode_id_by_hash(hash: &str, file_name: &str) -> Result<usize> {
+async fn get_episode_id_by_hash(
+    hash: &str,
+    file_name: &str,
+    linkage: &mut Linkage,
+) -> Result<usize> {
+    if let Some(episode_id) = linkage.get_files(hash) {
+        info!("Using remembered episode id: {}", episode_id);
+        return Ok(episode_id);
+    }
+
     let json = json!({
     "fileName":file_name,
     "fileHash":hash,
     "matchMode":"hashAndFileName"
     });
 
-    let res = CLIENT
-        .post("https://api.dandanplay.net/api/v2/match")
+    let res = api(Method::POST, "/api/v2/match")
         .header("Content-Type", "application/json")
         .json(&json)
         .send()
@@ -341,21 +775,237 @@ async fn get_episode_id_by_hash(hash: &str, file_name: &str) -> Result<usize> {
             data.matches[0].episode_id
         );
 
+        linkage.insert_files(hash, data.matches[0].episode_id);
         Ok(data.matches[0].episode_id)
     } else {
-        error!("Too many results");
-        Err(anyhow!("multiple matching episodes"))
+        info!("Multiple matching episodes, asking the user");
+
+        let items = data
+            .matches
+            .iter()
+            .map(|m| format!("{} - {}", m.anime_title, m.episode_title))
+            .collect();
+        let index = menu::choose("Danmaku: select the matching episode", items)
+            .await
+            .map_err(|_| anyhow!("multiple matching episodes"))?;
+        let episode_id = data.matches[index].episode_id;
+        info!("Selected episode id: {}", episode_id);
+
+        linkage.insert_files(hash, episode_id);
+        Ok(episode_id)
+    }
+}
+
+// Matches a stream by hash, or by its media title when it is too small to be
+// hashed or the hash is unknown.
+async fn get_episode_id_by_stream(
+    path: &str,
+    file_name: &str,
+    linkage: &mut Linkage,
+) -> Result<usize> {
+    use crate::{mpv::get_property_string, utils::get_stream_hash};
+    use std::result::Result::Ok;
+
+    let hashed = match get_stream_hash(path).await {
+        Ok(hash) => get_episode_id_by_hash(&hash, file_name, linkage).await,
+        Err(error) => Err(error),
+    };
+    match hashed {
+        Ok(episode_id) => Ok(episode_id),
+        Err(error) => {
+            info!("Failed to match by hash: {}", error);
+            osd_message("Danmaku: matching by media title");
+            let title =
+                get_property_string(c"media-title").unwrap_or_else(|| file_name.to_string());
+            get_episode_id_by_name(&title).await
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EpisodeSearch {
+    animes: Vec<EpisodeSearchAnime>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeSearchAnime {
+    #[serde(rename = "animeTitle")]
+    anime_title: String,
+    episodes: Vec<EpisodeSearchEpisode>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeSearchEpisode {
+    #[serde(rename = "episodeId")]
+    episode_id: usize,
+    #[serde(rename = "episodeTitle")]
+    episode_title: String,
+}
+
+// a match by name at least this good is taken without asking, when the next
+// best one is worse by the margin
+const ACCEPT_SCORE: f64 = 0.8;
+const ACCEPT_MARGIN: f64 = 0.15;
+const MAX_CANDIDATES: usize = 10;
+
+// Matches by the title, season and episode number in a file name or media
+// title. Candidates are scored by how similar their titles are, and the user
+// picks one when none of them is clearly right.
+async fn get_episode_id_by_name(name: &str) -> Result<usize> {
+    use crate::filename::{parse, similarity};
+
+    let parsed = parse(name);
+    if parsed.title.is_empty() {
+        return Err(anyhow!("no title found in {}", name));
+    }
+    info!("Matching by name: {:?}", parsed);
+
+    let res = api(Method::GET, "/api/v2/search/episodes")
+        .query(&[("anime", parsed.title.as_str())])
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        error!("Failed to search episodes, Status: {:?}", res.status());
+        return Err(anyhow!("failed to search episodes, try again later"));
+    }
+    let animes = res.json::<EpisodeSearch>().await?.animes;
+
+    let mut candidates = animes
+        .iter()
+        .filter_map(|anime| {
+            // too few episodes to be the one
+            let episode = match parsed.episode {
+                Some(number) => anime.episodes.get(number - 1)?,
+                None => anime.episodes.first()?,
+            };
+            let title = parse(&anime.anime_title);
+            let mut score = similarity(&parsed.title, &title.title);
+            if title.season.unwrap_or(1) != parsed.season.unwrap_or(1) {
+                score *= 0.7;
+            }
+            // movies have a single episode
+            if parsed.episode.is_none() && anime.episodes.len() == 1 {
+                score = (score + 0.1).min(1.);
+            }
+            Some((
+                score,
+                format!("{} - {}", anime.anime_title, episode.episode_title),
+                episode.episode_id,
+            ))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    candidates.truncate(MAX_CANDIDATES);
+    if let Some(duration) = video_duration() {
+        for (score, label, episode_id) in candidates.iter_mut().take(MAX_DURATION_CHECKS) {
+            if fits_video(*episode_id, duration).await == Some(false) {
+                info!("Comments of {} run past the end of the video", label);
+                *score *= 0.5;
+            }
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    }
+
+    match &candidates[..] {
+        [] => Err(anyhow!("no episode found for {}", parsed.title)),
+        [(score, label, episode_id), rest @ ..]
+            if *score >= ACCEPT_SCORE
+                && rest
+                    .first()
+                    .is_none_or(|next| score - next.0 >= ACCEPT_MARGIN) =>
+        {
+            info!("Matched {} by name, score {:.2}", label, score);
+            Ok(*episode_id)
+        }
+        _ => {
+            info!("No confident match by name, asking the user");
+            let items = candidates
+                .iter()
+                .map(|(score, label, _)| format!("{} ({:.0}%)", label, score * 100.))
+                .collect();
+            let index = menu::choose(
+                &format!("Danmaku: select the episode of {}", parsed.title),
+                items,
+            )
+            .await
+            .map_err(|_| anyhow!("no episode selected"))?;
+            info!("Selected episode id: {}", candidates[index].2);
+            Ok(candidates[index].2)
+        }
+    }
+}
+
+// Comments may run past the end of a video by this share of its duration,
+// and at least by MIN_OVERRUN seconds, before they are taken to be of
+// another episode.
+const OVERRUN: f64 = 0.1;
+const MIN_OVERRUN: f64 = 60.;
+// each check may cost a request
+const MAX_DURATION_CHECKS: usize = 3;
+
+// Whether comments ending at `last` plausibly belong to a video of
+// `duration` seconds.
+pub fn fits_duration(last: f64, duration: f64) -> bool {
+    last <= duration + (duration * OVERRUN).max(MIN_OVERRUN)
+}
+
+fn video_duration() -> Option<f64> {
+    get_property_f64(c"duration").filter(|&duration| duration > 0.)
+}
+
+// Whether the comments of an episode end with the video, None when that can
+// not be told. Fetched comments are cached, as the episode is usually one of
+// the candidates checked and would be fetched again right after.
+async fn fits_video(episode_id: usize, duration: f64) -> Option<bool> {
+    use std::result::Result::Ok;
+
+    let comments = match CachedComments::load(episode_id).await {
+        Ok(Some(cached)) => cached.comments,
+        _ => {
+            let cached = CachedComments {
+                fetched: SystemTime::now(),
+                stable: false,
+                comments: CommentResponse::get(episode_id).await.ok()?.comments,
+            };
+            if let Err(error) = cached.save(episode_id).await {
+                log_error(&error);
+            }
+            cached.comments
+        }
+    };
+    let last = comments
+        .iter()
+        .filter_map(|comment| comment.p.split(',').next()?.parse::<f64>().ok())
+        .reduce(f64::max)?;
+    Some(fits_duration(last, duration))
+}
+
+// The first of `candidates` whose comments fit the video, or the first of
+// them when none does or the duration is not known.
+async fn first_fitting(candidates: &[usize]) -> Option<usize> {
+    let first = *candidates.first()?;
+    let Some(duration) = video_duration() else {
+        return Some(first);
+    };
+    for &episode_id in candidates.iter().take(MAX_DURATION_CHECKS) {
+        if fits_video(episode_id, duration).await == Some(false) {
+            info!(
+                "Comments of episode {} run past the end of the video",
+                episode_id
+            );
+        } else {
+            return Some(episode_id);
+        }
     }
+    Some(first)
 }
 
 // total shit
 // shitshitshitshitshitshitshitshitshitshitshit
 //
 async fn get_episode_id_by_info(ep_info: &EpInfo, linkage: &mut Linkage) -> Result<usize> {
-    use crate::utils::{SearchRes, get_dan_sum, get_em_sum};
+    use crate::utils::SearchRes;
     use std::result::Result::Ok;
-    use url::form_urlencoded;
-
     let ep_type = &ep_info.r#type;
     let host = &ep_info.host;
     let ep_snum = ep_info.item_info.sn_index;
@@ -367,15 +1017,11 @@ async fn get_episode_id_by_info(ep_info: &EpInfo, linkage: &mut Linkage) -> Resu
         return Ok(format!("{}{:04}", id.anime_id, ep_num as i64 + id.offset).parse::<usize>()?);
     }
 
-    let encoded_name: String =
-        form_urlencoded::byte_serialize(ep_info.get_series_name().as_bytes()).collect();
-    let url = format!(
-        "https://api.dandanplay.net/api/v2/search/anime?keyword={}&type={}",
-        encoded_name, ep_type
-    );
-
-    let res = CLIENT
-        .get(url)
+    let res = api(Method::GET, "/api/v2/search/anime")
+        .query(&[
+            ("keyword", ep_info.get_series_name().as_str()),
+            ("type", ep_type.as_str()),
+        ])
         .header("Content-Type", "application/json")
         .send()
         .await?;
@@ -395,7 +1041,7 @@ async fn get_episode_id_by_info(ep_info: &EpInfo, linkage: &mut Linkage) -> Resu
         return Err(anyhow!("no matching episode with info"));
     }
 
-    if ["true", "on", "enable"].contains(&options::OPTIONS.log.to_ascii_lowercase().as_str()) {
+    if ["true", "on", "enable"].contains(&options::get().log.to_ascii_lowercase().as_str()) {
         let dandan_search = data
             .animes
             .iter()
@@ -410,253 +1056,63 @@ async fn get_episode_id_by_info(ep_info: &EpInfo, linkage: &mut Linkage) -> Resu
         return Err(anyhow!("no matching episode with info"));
     };
 
-    let (mut ani_id, mut ep_id) = (0u64, 0u64);
-
     if ep_type == "ova" {
         // ova只按照ep_num排序，结果无法预期
-        (ani_id, ep_id) = (data.animes[ep_num as usize - 1].anime_id, ep_num);
-
-        info!("Success, ova episode id: {}{:04}", ani_id, ep_id);
-        return Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?);
+        // the other results are tried when its comments do not fit the video
+        // specials are often numbered from 0, which no result is
+        let Some(picked) = (ep_num as usize).checked_sub(1) else {
+            error!("No matching OVA");
+            return Err(anyhow!("no matching episode with info"));
+        };
+        let candidates = std::iter::once(picked)
+            .chain((0..data.animes.len()).filter(|&i| i != picked))
+            .map(|i| data.animes[i].anime_id as usize * 10000 + ep_num as usize)
+            .collect::<Vec<_>>();
+        let episode_id = first_fitting(&candidates)
+            .await
+            .ok_or_else(|| anyhow!("no matching episode with info"))?;
+
+        info!("Success, ova episode id: {}", episode_id);
+        return Ok(episode_id);
     };
 
     if ep_type == "movie" {
-        // 电影永远只取第一个结果
-        (ani_id, ep_id) = (data.animes[0].anime_id, 1u64);
-
-        info!("Success, movie episode id: {}{:04}", ani_id, ep_id);
-        return Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?);
+        // the first result, unless its comments do not fit the video
+        let candidates = data
+            .animes
+            .iter()
+            .map(|anime| anime.anime_id as usize * 10000 + 1)
+            .collect::<Vec<_>>();
+        let episode_id = first_fitting(&candidates)
+            .await
+            .ok_or_else(|| anyhow!("no matching episode with info"))?;
+
+        info!("Success, movie episode id: {}", episode_id);
+        return Ok(episode_id);
     };
 
     let ep_num_list = get_series_info(ep_info).await?;
 
-    if ep_num_list.is_empty() {
-        error!("Ooops, series info fetching from Emby is empty");
-        return Err(anyhow!("no matching episode with info"));
-    }
-
-    // 如果季数匹配，则直接返回结果
-    if data.animes.len() as u64 == ep_num_list.last().unwrap().0 {
-        (ani_id, ep_id) = (data.animes[ep_snum as usize - 1].anime_id, ep_num);
-
-        info!("Success, tv series episode id: {}{:04}", ani_id, ep_id);
-
-        let ani_off = AnimeOffset {
-            anime_id: ani_id as usize,
-            offset: 0i64,
-        };
-        linkage.insert_seasons(host, seid, ani_off);
-        return Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?);
+    let alignment = match align(&data.animes, &ep_num_list, ep_snum, ep_num) {
+        Ok(alignment) => alignment,
+        Err(error) => {
+            error!("Failed to align seasons: {}", error);
+            return Err(error.into());
+        }
     };
-
-    if get_dan_sum(&data.animes, ep_snum)? == get_em_sum(&ep_num_list, ep_snum)? {
-        (ani_id, ep_id) = (data.animes[ep_snum as usize - 1].anime_id, ep_num);
-
-        info!("Success, tv series episode id: {}{:04}", ani_id, ep_id);
-
-        let ani_off = AnimeOffset {
-            anime_id: ani_id as usize,
-            offset: 0i64,
-        };
-        linkage.insert_seasons(host, seid, ani_off);
-        return Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?);
-    }
-
-    if ep_num_list[0].0 != 1 && (ep_snum as u64) != ep_num_list.last().unwrap().0 {
-        error!("Hard to decide, insufficient info");
-        return Err(anyhow!("need more info, skip"));
-    }
-
-    if ep_num_list[0].0 != 1 && (data.animes.len() as u64) < ep_num_list.last().unwrap().0 {
-        error!("Hard to decide, insufficient info");
-        return Err(anyhow!("need more info, skip"));
-    }
-
-    if ep_num_list[0].0 != 1
-        && data.animes[ep_snum as usize].episode_count == ep_num_list.last().unwrap().1
-    {
-        (ani_id, ep_id) = (data.animes[ep_snum as usize].anime_id, ep_num);
-        info!("Success, tv series episode id: {}{:04}", ani_id, ep_id);
-
+    if let Some(offset) = alignment.offset {
         let ani_off = AnimeOffset {
-            anime_id: ani_id as usize,
-            offset: 0i64,
+            anime_id: alignment.anime_id as usize,
+            offset,
         };
         linkage.insert_seasons(host, seid, ani_off);
-        return Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?);
-    }
-
-    if ep_num_list[0].0 != 1
-        && data.animes[ep_snum as usize].episode_count
-            + data.animes[ep_snum as usize - 1].episode_count
-            == ep_num_list.last().unwrap().1
-    {
-        if ep_num <= data.animes[ep_snum as usize - 1].episode_count {
-            (ani_id, ep_id) = (data.animes[ep_snum as usize - 1].anime_id, ep_num);
-            info!("Success, tv series episode id: {}{:04}", ani_id, ep_id);
-
-            let ani_off = AnimeOffset {
-                anime_id: ani_id as usize,
-                offset: 0i64,
-            };
-            linkage.insert_seasons(host, seid, ani_off);
-            return Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?);
-        } else {
-            (ani_id, ep_id) = (
-                data.animes[ep_snum as usize].anime_id,
-                ep_num - data.animes[ep_snum as usize - 1].episode_count,
-            );
-            info!("Success, tv series episode id: {}{:04}", ani_id, ep_id);
-            return Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?);
-        }
-    }
-
-    if ep_num_list[0].0 != 1 {
-        error!("Hard to decide, insufficient info");
-        return Err(anyhow!("need more info, skip"));
-    }
-
-    if get_dan_sum(&data.animes, data.animes.len() as i64)?
-        != get_em_sum(&ep_num_list, ep_num_list.len() as i64)?
-    {
-        error!("Hard to decide, insufficient info");
-        return Err(anyhow!("need more info, skip"));
-    }
-
-    // SHIT
-    //
-    // 求解季数被合并的情况
-    if data.animes.len() > ep_num_list.len() {
-        let offset = data.animes.len() - ep_num_list.len();
-
-        'outer: for i in 0..=offset {
-            if get_dan_sum(&data.animes, ep_snum + i as i64)? == get_em_sum(&ep_num_list, ep_snum)?
-            {
-                for x in 0..=i {
-                    if get_dan_sum(&data.animes, ep_snum - 1 + x as i64)?
-                        == get_em_sum(&ep_num_list, ep_snum - 1)?
-                    {
-                        if i == x {
-                            (ani_id, ep_id) =
-                                (data.animes[ep_snum as usize - 1 + i].anime_id, ep_num);
-                            let ani_off = AnimeOffset {
-                                anime_id: ani_id as usize,
-                                offset: 0i64,
-                            };
-                            linkage.insert_seasons(host, seid, ani_off);
-                            break 'outer;
-                        }
-
-                        if i == x + 1
-                            && ep_num <= data.animes[ep_snum as usize - 1 + x].episode_count
-                        {
-                            (ani_id, ep_id) =
-                                (data.animes[ep_snum as usize - 1 + x].anime_id, ep_num);
-                            break 'outer;
-                        }
-
-                        if i == x + 1
-                            && ep_num > data.animes[ep_snum as usize - 1 + x].episode_count
-                        {
-                            (ani_id, ep_id) = (
-                                data.animes[ep_snum as usize + x].anime_id,
-                                ep_num - data.animes[ep_snum as usize - 1 + x].episode_count,
-                            );
-                            break 'outer;
-                        }
-
-                        if i == x + 2
-                            && ep_num <= data.animes[ep_snum as usize - 1 + x].episode_count
-                        {
-                            (ani_id, ep_id) =
-                                (data.animes[ep_snum as usize - 1 + x].anime_id, ep_num);
-                            break 'outer;
-                        }
-
-                        if i == x + 2
-                            && ep_num
-                                <= data.animes[ep_snum as usize - 1 + x].episode_count
-                                    + data.animes[ep_snum as usize + x].episode_count
-                        {
-                            (ani_id, ep_id) = (
-                                data.animes[ep_snum as usize + x].anime_id,
-                                ep_num - data.animes[ep_snum as usize - 1 + x].episode_count,
-                            );
-                            break 'outer;
-                        }
-
-                        if i == x + 2 {
-                            (ani_id, ep_id) = (
-                                data.animes[ep_snum as usize + x + 1].anime_id,
-                                ep_num
-                                    - data.animes[ep_snum as usize - 1 + x].episode_count
-                                    - data.animes[ep_snum as usize + x].episode_count,
-                            );
-                            break 'outer;
-                        }
-                        error!("Too many results");
-                        return Err(anyhow!("too many results"));
-                    }
-                }
-            }
-        }
-    }
-
-    // shit
-    //
-    // 求解季数被拆开的情况
-    if data.animes.len() < ep_num_list.len() {
-        'outer: for i in 1..=data.animes.len() {
-            if get_dan_sum(&data.animes, i as i64)? == get_em_sum(&ep_num_list, ep_snum)? {
-                if get_dan_sum(&data.animes, i as i64 - 1)?
-                    == get_em_sum(&ep_num_list, ep_snum - 1)?
-                {
-                    (ani_id, ep_id) = (data.animes[i].anime_id, ep_num);
-                    let ani_off = AnimeOffset {
-                        anime_id: ani_id as usize,
-                        offset: 0i64,
-                    };
-                    linkage.insert_seasons(host, seid, ani_off);
-                    break 'outer;
-                }
-
-                if get_dan_sum(&data.animes, i as i64 - 1)?
-                    == get_em_sum(&ep_num_list, ep_snum - 2)?
-                {
-                    (ani_id, ep_id) = (
-                        data.animes[i].anime_id,
-                        ep_num + ep_num_list[ep_snum as usize - 2].1,
-                    );
-                    let ani_off = AnimeOffset {
-                        anime_id: ani_id as usize,
-                        offset: 0i64,
-                    };
-                    linkage.insert_seasons(host, seid, ani_off);
-                    break 'outer;
-                }
-            }
-
-            if get_dan_sum(&data.animes, i as i64 - 1)? == get_em_sum(&ep_num_list, ep_snum - 1)?
-                && get_em_sum(&ep_num_list, ep_snum + 1)? == get_dan_sum(&data.animes, i as i64)?
-            {
-                (ani_id, ep_id) = (data.animes[i].anime_id, ep_num);
-                let ani_off = AnimeOffset {
-                    anime_id: ani_id as usize,
-                    offset: 0i64,
-                };
-                linkage.insert_seasons(host, seid, ani_off);
-                break 'outer;
-            }
-        }
     }
+    info!(
+        "Success, tv series episode id: {}{:04}",
+        alignment.anime_id, alignment.episode
+    );
 
-    if (ani_id, ep_id) == (0, 0) {
-        error!("No matching result");
-        return Err(anyhow!("not matching episode with info"));
-    }
-    info!("Success, tv series episode id: {}{:04}", ani_id, ep_id);
-
-    Ok(format!("{}{:04}", ani_id, ep_id).parse::<usize>()?)
+    Ok(format!("{}{:04}", alignment.anime_id, alignment.episode).parse::<usize>()?)
 }
 
 #[derive(Debug, Deserialize)]
@@ -671,14 +1127,19 @@ struct BEpisodes {
 
 #[derive(Deserialize, Debug)]
 struct BEpisode {
+    #[serde(rename = "episodeId")]
+    episode_id: usize,
+    #[serde(rename = "episodeTitle")]
+    episode_title: String,
     #[serde(rename = "episodeNumber")]
     episode_number: String,
 }
 
 pub async fn _get_episode_num_dan(epid: usize) -> Result<u64> {
     let anime_id = epid / 10000;
-    let bangumi_url = format!("https://api.dandanplay.net/api/v2/bangumi/{}", anime_id);
-    let res = CLIENT.get(bangumi_url).send().await?;
+    let res = api(Method::GET, &format!("/api/v2/bangumi/{}", anime_id))
+        .send()
+        .await?;
 
     if !res.status().is_success() {
         error!(
@@ -702,3 +1163,60 @@ pub async fn _get_episode_num_dan(epid: usize) -> Result<u64> {
     });
     Ok(sum)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn comments() -> Vec<Comment> {
+        ["1.5,1,16777215,[BiliBili]abc", "3,5,255,[Gamer]def"]
+            .iter()
+            .map(|p| Comment {
+                p: p.to_string(),
+                m: "233".to_string(),
+            })
+            .collect()
+    }
+
+    fn payload<T: Serialize>(value: &T) -> Vec<u8> {
+        bincode::serde::encode_to_vec(value, bincode::config::legacy()).unwrap()
+    }
+
+    #[test]
+    fn migrates_cached_comments() {
+        let fetched = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let cached = CachedComments {
+            fetched,
+            stable: true,
+            comments: comments(),
+        };
+        // a comment response as the API sent it, cached before the container
+        // and the fetch time
+        let response = payload(&comments());
+        let cases = [
+            (store::encode(&cached).unwrap(), fetched, true),
+            (store::container(1, &payload(&cached)), fetched, true),
+            (payload(&cached), fetched, true),
+            (response, SystemTime::UNIX_EPOCH, false),
+        ];
+        for (contents, fetched, stable) in cases {
+            let decoded = store::decode::<CachedComments>(&contents).unwrap();
+            assert_eq!(decoded.fetched, fetched);
+            assert_eq!(decoded.stable, stable);
+            let messages = decoded
+                .comments
+                .iter()
+                .map(|comment| (comment.p.as_str(), comment.m.as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                messages,
+                [
+                    ("1.5,1,16777215,[BiliBili]abc", "233"),
+                    ("3,5,255,[Gamer]def", "233")
+                ]
+            );
+        }
+        assert!(store::decode::<CachedComments>(&payload(&(1u64, 2u32))).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{
+    dandanplay::Danmaku,
+    options::{self, Options},
+};
+
+// comments about this wide, in font sizes, read best, longer ones gain nothing
+const PREFERRED_WIDTH: f64 = 12.;
+
+// Blocks the least interesting comments of every second of video that has
+// more than `density` comments left. `danmaku` must be sorted by time.
+pub fn limit(danmaku: &mut [Danmaku]) {
+    let options = options::get();
+    if options.density == 0 {
+        return;
+    }
+
+    let mut start = 0;
+    while start < danmaku.len() {
+        let second = danmaku[start].time.floor();
+        let end =
+            start + danmaku[start..].partition_point(|comment| comment.time.floor() == second);
+        let mut shown = (start..end)
+            .filter(|&i| !danmaku[i].blocked)
+            .collect::<Vec<_>>();
+        if shown.len() > options.density {
+            shown.sort_by(|&a, &b| {
+                priority(&danmaku[b], &options).total_cmp(&priority(&danmaku[a], &options))
+            });
+            for &i in &shown[options.density..] {
+                danmaku[i].blocked = true;
+            }
+        }
+        start = end;
+    }
+}
+
+fn priority(comment: &Danmaku, options: &Options) -> f64 {
+    let preferred = options.density_prefer.len();
+    let source = options
+        .density_prefer
+        .iter()
+        .position(|&source| source == comment.source)
+        .map_or(0., |rank| (preferred - rank) as f64 / preferred as f64 * 2.);
+    let duplicates = (comment.merged as f64).log2();
+    let length = comment.width.min(PREFERRED_WIDTH) / PREFERRED_WIDTH;
+    let white = comment.r == 0xff && comment.g == 0xff && comment.b == 0xff;
+    let color = if white { 0. } else { 0.5 };
+
+    source + duplicates + length + color
+}
@@ -1,14 +1,105 @@
 use super::utils::CLIENT;
 use anyhow::{Context, Ok, Result, anyhow};
 use regex::Regex;
+use reqwest::RequestBuilder;
 use serde::Deserialize;
 use std::fmt::Display;
 use tracing::{error, info};
 use url::Url;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ServerKind {
+    Emby,
+    Jellyfin,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MediaServer {
+    pub kind: ServerKind,
+    // scheme, host, port and the path prefix the server is mounted at
+    pub base_url: String,
+    pub api_key: String,
+}
+
+impl Default for MediaServer {
+    fn default() -> Self {
+        Self {
+            kind: ServerKind::Emby,
+            base_url: "unknown".to_string(),
+            api_key: "unknown".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PublicSystemInfo {
+    #[serde(default, rename = "ProductName")]
+    product_name: String,
+}
+
+impl MediaServer {
+    // Emby ids are numeric while Jellyfin uses GUIDs, ask the server when in doubt.
+    async fn detect(base_url: String, item_id: &str, api_key: String) -> Self {
+        let kind = if item_id.chars().all(|c| c.is_ascii_digit()) {
+            ServerKind::Emby
+        } else {
+            match Self::probe(&base_url).await {
+                Some(kind) => kind,
+                None => ServerKind::Jellyfin,
+            }
+        };
+        info!("Media server: {:?}", kind);
+
+        Self {
+            kind,
+            base_url,
+            api_key,
+        }
+    }
+
+    async fn probe(base_url: &str) -> Option<ServerKind> {
+        let info = CLIENT
+            .get(format!("{}/System/Info/Public", base_url))
+            .send()
+            .await
+            .ok()?
+            .json::<PublicSystemInfo>()
+            .await
+            .ok()?;
+
+        if info.product_name.to_ascii_lowercase().contains("jellyfin") {
+            Some(ServerKind::Jellyfin)
+        } else {
+            Some(ServerKind::Emby)
+        }
+    }
+
+    fn get(&self, path: &str) -> RequestBuilder {
+        match self.kind {
+            ServerKind::Emby => {
+                // the api of Emby lives under /emby, which may already be part of the url
+                let prefix = if self.base_url.ends_with("/emby") {
+                    ""
+                } else {
+                    "/emby"
+                };
+                CLIENT
+                    .get(format!("{}{}{}", self.base_url, prefix, path))
+                    .query(&[("reqformat", "json")])
+                    .header("X-Emby-Token", &self.api_key)
+            }
+            ServerKind::Jellyfin => CLIENT.get(format!("{}{}", self.base_url, path)).header(
+                "Authorization",
+                format!("MediaBrowser Token=\"{}\"", self.api_key),
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct P3 {
     pub host: String,
+    pub base_url: String,
     pub item_id: String,
     pub api_key: String,
 }
@@ -16,38 +107,40 @@ pub(crate) struct P3 {
 pub(crate) fn extract_params(video_url: &str) -> Result<P3> {
     let url = Url::parse(video_url)?;
 
-    if url.host_str().is_none() {
+    let Some(host_str) = url.host_str() else {
         error!("Host not found");
-    }
+        return Err(anyhow!("host not found"));
+    };
 
-    // host
-    let host = format!(
-        "{}://{}",
-        url.scheme(),
-        url.host_str().expect("host not found")
-    );
+    // host, without the port as items and seasons have always been
+    // remembered under it
+    let host = format!("{}://{}", url.scheme(), host_str);
 
-    // api_key
+    // api_key, Jellyfin also accepts ApiKey
     let Some(api_key) = url
         .query_pairs()
-        .find(|(key, _)| key == "api_key")
+        .find(|(key, _)| key == "api_key" || key == "ApiKey")
         .map(|(_, value)| value)
     else {
         error!("api_key not founf");
         return Err(anyhow!("api_key not found"));
     };
 
-    let pattern = Regex::new(r"^.*/videos/(\d+)/.*")?;
+    // Emby item ids are numeric, Jellyfin ones are GUIDs with or without dashes
+    let pattern = Regex::new(
+        r"(?i)^(.*?)/videos/([0-9a-f]{8}-?[0-9a-f]{4}-?[0-9a-f]{4}-?[0-9a-f]{4}-?[0-9a-f]{12}|\d+)/.*",
+    )?;
 
     // item_id
-    let item_id = if let Some(captures) = pattern.captures(url.path()) {
-        String::from(&captures[1])
+    let (prefix, item_id) = if let Some(captures) = pattern.captures(url.path()) {
+        (String::from(&captures[1]), String::from(&captures[2]))
     } else {
         error!("ItemId not found");
         return Err(anyhow!("item_id not found"));
     };
 
     Ok(P3 {
+        base_url: format!("{}{}", url.origin().ascii_serialization(), prefix),
         host,
         item_id,
         api_key: api_key.to_string(),
@@ -83,7 +176,7 @@ impl Default for ItemInfo {
 pub(crate) struct EpInfo {
     pub r#type: String,
     pub host: String,
-    pub api_key: String,
+    pub server: MediaServer,
     pub item_info: ItemInfo,
     pub status: bool,
 }
@@ -93,7 +186,7 @@ impl Default for EpInfo {
         Self {
             r#type: "unknown".to_string(),
             host: "unknown".to_string(),
-            api_key: "unknown".to_string(),
+            server: MediaServer::default(),
             item_info: ItemInfo::default(),
             status: false,
         }
@@ -103,7 +196,8 @@ impl Default for EpInfo {
 impl Display for EpInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = format!(
-            "[Type: {}  Name: {}  Series Name: {}  Season Number: {}  Episode Number: {}  SeriesId: {}  SeasonId: {}  Status: {}]",
+            "[Server: {:?}  Type: {}  Name: {}  Series Name: {}  Season Number: {}  Episode Number: {}  SeriesId: {}  SeasonId: {}  Status: {}]",
+            self.server.kind,
             self.r#type,
             self.item_info.name,
             self.item_info.ss_name,
@@ -179,6 +273,7 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
 
     let P3 {
         host,
+        base_url,
         item_id,
         api_key,
     } = match extract_params(video_url) {
@@ -186,17 +281,17 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
         Err(_) => return Ok(EpInfo::default()),
     };
 
-    let url = format!("{}/emby/Items?Ids={}&reqformat=json", host, item_id);
+    let server = MediaServer::detect(base_url, &item_id, api_key).await;
 
-    let response = CLIENT
-        .get(url)
-        .header("X-Emby-Token", &api_key)
+    let response = server
+        .get("/Items")
+        .query(&[("Ids", &item_id)])
         .send()
         .await?;
 
     if !response.status().is_success() {
         error!(
-            "Failed to fetch ep info from Emby server, Status: {:?}",
+            "Failed to fetch ep info from media server, Status: {:?}",
             response.status()
         );
 
@@ -216,7 +311,7 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
             Ok(EpInfo {
                 r#type: "ova".to_string(),
                 host,
-                api_key,
+                server,
                 item_info: ItemInfo {
                     name: epdata.items[0].name.clone(),
                     ss_name: epdata.items[0].series_name.clone(),
@@ -232,7 +327,7 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
             Ok(EpInfo {
                 r#type: "tvseries".to_string(),
                 host,
-                api_key,
+                server,
                 item_info: ItemInfo {
                     name: epdata.items[0].name.clone(),
                     ss_name: epdata.items[0].series_name.clone(),
@@ -249,7 +344,7 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
         Ok(EpInfo {
             r#type: "movie".to_string(),
             host,
-            api_key,
+            server,
             item_info: ItemInfo {
                 name: epdata.items[0].name.clone(),
                 item_id,
@@ -296,21 +391,17 @@ struct Episode {
 pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>> {
     use std::result::Result::Ok;
 
-    let host = ep_info.host.clone();
-    let api_key = ep_info.api_key.clone();
+    let server = &ep_info.server;
     let series_id = ep_info.item_info.ss_id.clone();
 
-    let seasons_url = format!("{}/emby/Shows/{}/Seasons?reqformat=json", host, series_id);
-
-    let res = CLIENT
-        .get(seasons_url)
-        .header("X-Emby-Token", &api_key)
+    let res = server
+        .get(&format!("/Shows/{}/Seasons", series_id))
         .send()
         .await?;
 
     if !res.status().is_success() {
         error!(
-            "Failed to fetch seasons info from Emby server, Status: {:?}",
+            "Failed to fetch seasons info from media server, Status: {:?}",
             res.status()
         );
 
@@ -332,19 +423,15 @@ pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>>
         if season.season_num != 0 && season.season_num > episodes_list.last().unwrap_or(&(0, 0)).0 {
             let sid = season.season_id;
 
-            let episodes_url = format!(
-                "{}/emby/Shows/{}/Episodes?SeasonId={}&reqformat=json",
-                host, series_id, sid
-            );
-            let res = CLIENT
-                .get(episodes_url)
-                .header("X-Emby-Token", &api_key)
+            let res = server
+                .get(&format!("/Shows/{}/Episodes", series_id))
+                .query(&[("SeasonId", &sid)])
                 .send()
                 .await?;
 
             if !res.status().is_success() {
                 error!(
-                    "Failed to fetch episodes info from Emby server, Status: {}",
+                    "Failed to fetch episodes info from media server, Status: {}",
                     res.status()
                 );
 
@@ -370,7 +457,7 @@ pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>>
             episodes_list.push((season.season_num, sum));
         }
     }
-    info!("Episodes list from Emby: {:?}", episodes_list);
+    info!("Episodes list from {:?}: {:?}", server.kind, episodes_list);
 
     Ok(episodes_list)
 }
@@ -378,23 +465,17 @@ pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>>
 pub(crate) async fn _get_episode_num_emby(ep_info: &EpInfo) -> Result<u64> {
     let series_id = ep_info.item_info.ss_id.clone();
     let season_id = ep_info.item_info.se_id.clone();
-    let host = ep_info.host.clone();
-    let api_key = ep_info.api_key.clone();
-
-    let url = format!(
-        "{}/emby/Shows/{}/Episodes?SeasonId={}&reqformat=json",
-        host, series_id, season_id
-    );
 
-    let res = CLIENT
-        .get(url)
-        .header("X-Emby-Token", &api_key)
+    let res = ep_info
+        .server
+        .get(&format!("/Shows/{}/Episodes", series_id))
+        .query(&[("SeasonId", &season_id)])
         .send()
         .await?;
 
     if !res.status().is_success() {
         error!(
-            "Failed to fetch seasons info from Emby server, Status: {:?}",
+            "Failed to fetch seasons info from media server, Status: {:?}",
             res.status()
         );
 
@@ -1,8 +1,14 @@
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
+#![allow(clippy::missing_safety_doc)]
 
-use std::os::raw::{c_char, c_int, c_void};
+#[cfg(not(target_os = "windows"))]
+use std::{mem::transmute, sync::LazyLock};
+use std::{
+    os::raw::{c_char, c_int, c_void},
+    ptr::{null, null_mut},
+};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -135,172 +141,113 @@ pub struct mpv_event {
     pub data: *mut c_void,
 }
 
+// Defines a wrapper for each function of the client API. mpv fills in the
+// exported `pfn_` pointers on Windows, elsewhere the functions are looked up
+// in the program that loaded the plugin. Either way nothing is linked against
+// libmpv. The plugin makes sure with `missing` that every function is there,
+// only danmaku2ass, which never calls them, gets the given value instead.
+macro_rules! mpv_functions {
+    ($(
+        $pfn:ident fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)? = $missing:expr;
+    )*) => {
+        $(
+            #[cfg(target_os = "windows")]
+            #[unsafe(no_mangle)]
+            static mut $pfn: Option<extern "C" fn($($ty),*) $(-> $ret)?> = None;
+
+            pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                #[cfg(target_os = "windows")]
+                let function = unsafe { $pfn };
+                #[cfg(not(target_os = "windows"))]
+                let function = {
+                    static FUNCTION: LazyLock<Option<extern "C" fn($($ty),*) $(-> $ret)?>> =
+                        LazyLock::new(|| {
+                            lookup(concat!(stringify!($name), "\0")).map(|symbol| unsafe {
+                                transmute::<*mut c_void, extern "C" fn($($ty),*) $(-> $ret)?>(
+                                    symbol,
+                                )
+                            })
+                        });
+                    *FUNCTION
+                };
+                match function {
+                    Some(function) => function($($arg),*),
+                    None => $missing,
+                }
+            }
+        )*
+
+        // The functions the program that loaded the plugin does not provide.
+        pub fn missing() -> Vec<&'static str> {
+            let mut missing = Vec::new();
+            $(
+                #[cfg(target_os = "windows")]
+                let found = unsafe { $pfn }.is_some();
+                #[cfg(not(target_os = "windows"))]
+                let found = lookup(concat!(stringify!($name), "\0")).is_some();
+                if !found {
+                    missing.push(stringify!($name));
+                }
+            )*
+            missing
+        }
+    };
+}
+
+// `name` is nul terminated.
 #[cfg(not(target_os = "windows"))]
-unsafe extern "C" {
-    pub fn mpv_error_string(error: c_int) -> *const c_char;
-    pub fn mpv_free(data: *mut c_void);
-    pub fn mpv_client_name(ctx: *mut mpv_handle) -> *const c_char;
-    pub fn mpv_free_node_contents(node: *mut mpv_node);
-    pub fn mpv_command(ctx: *mut mpv_handle, args: *mut *const c_char) -> c_int;
-    pub fn mpv_command_node(
+fn lookup(name: &str) -> Option<*mut c_void> {
+    unsafe extern "C" {
+        fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    }
+    const RTLD_LAZY: c_int = 1;
+
+    // the program itself and the libraries it was linked with
+    let program = unsafe { dlopen(null(), RTLD_LAZY) };
+    if program.is_null() {
+        return None;
+    }
+    let symbol = unsafe { dlsym(program, name.as_ptr().cast()) };
+    (!symbol.is_null()).then_some(symbol)
+}
+
+const UNINITIALIZED: c_int = mpv_error::MPV_ERROR_UNINITIALIZED as c_int;
+
+mpv_functions! {
+    pfn_mpv_error_string fn mpv_error_string(error: c_int) -> *const c_char =
+        c"not running in mpv".as_ptr();
+    pfn_mpv_free fn mpv_free(data: *mut c_void) = ();
+    pfn_mpv_client_name fn mpv_client_name(ctx: *mut mpv_handle) -> *const c_char = null();
+    pfn_mpv_free_node_contents fn mpv_free_node_contents(node: *mut mpv_node) = ();
+    pfn_mpv_command fn mpv_command(ctx: *mut mpv_handle, args: *mut *const c_char) -> c_int =
+        UNINITIALIZED;
+    pfn_mpv_command_node fn mpv_command_node(
         ctx: *mut mpv_handle,
         args: *mut mpv_node,
-        result: *mut mpv_node,
-    ) -> c_int;
-    pub fn mpv_command_ret(
+        result: *mut mpv_node
+    ) -> c_int = UNINITIALIZED;
+    pfn_mpv_command_ret fn mpv_command_ret(
         ctx: *mut mpv_handle,
         args: *mut *const c_char,
-        result: *mut mpv_node,
-    ) -> c_int;
-    pub fn mpv_get_property(
-        ctx: *mut mpv_handle,
-        name: *const c_char,
-        format: mpv_format,
-        data: *mut c_void,
-    ) -> c_int;
-    pub fn mpv_observe_property(
-        ctx: *mut mpv_handle,
-        reply_userdata: u64,
-        name: *const c_char,
-        format: mpv_format,
-    ) -> c_int;
-    pub fn mpv_command_string(ctx: *mut mpv_handle, args: *const c_char) -> c_int;
-    pub fn mpv_event_name(event: mpv_event_id) -> *const c_char;
-    pub fn mpv_wait_event(ctx: *mut mpv_handle, timeout: f64) -> *mut mpv_event;
-    pub fn mpv_wakeup(ctx: *mut mpv_handle);
-}
-
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_error_string: Option<extern "C" fn(error: c_int) -> *const c_char> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_free: Option<extern "C" fn(data: *mut c_void)> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_client_name: Option<extern "C" fn(ctx: *mut mpv_handle) -> *const c_char> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_free_node_contents: Option<extern "C" fn(node: *mut mpv_node)> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_command: Option<
-    extern "C" fn(ctx: *mut mpv_handle, args: *mut *const c_char) -> c_int,
-> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_command_node: Option<
-    extern "C" fn(ctx: *mut mpv_handle, args: *mut mpv_node, result: *mut mpv_node) -> c_int,
-> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_command_ret: Option<
-    extern "C" fn(ctx: *mut mpv_handle, args: *mut *const c_char, result: *mut mpv_node) -> c_int,
-> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_get_property: Option<
-    extern "C" fn(
+        result: *mut mpv_node
+    ) -> c_int = UNINITIALIZED;
+    pfn_mpv_get_property fn mpv_get_property(
         ctx: *mut mpv_handle,
         name: *const c_char,
         format: mpv_format,
-        data: *mut c_void,
-    ) -> c_int,
-> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_observe_property: Option<
-    extern "C" fn(
+        data: *mut c_void
+    ) -> c_int = UNINITIALIZED;
+    pfn_mpv_observe_property fn mpv_observe_property(
         ctx: *mut mpv_handle,
         reply_userdata: u64,
         name: *const c_char,
-        format: mpv_format,
-    ) -> c_int,
-> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_command_string: Option<
-    extern "C" fn(ctx: *mut mpv_handle, args: *const c_char) -> c_int,
-> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-static mut pfn_mpv_event_name: Option<extern "C" fn(event: mpv_event_id) -> *const c_char> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-pub static mut pfn_mpv_wait_event: Option<
-    extern "C" fn(ctx: *mut mpv_handle, timeout: f64) -> *mut mpv_event,
-> = None;
-#[cfg(target_os = "windows")]
-#[unsafe(no_mangle)]
-pub static mut pfn_mpv_wakeup: Option<extern "C" fn(ctx: *mut mpv_handle)> = None;
-
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_error_string(error: c_int) -> *const c_char {
-    unsafe { pfn_mpv_error_string.unwrap()(error) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_free(data: *mut c_void) {
-    unsafe { pfn_mpv_free.unwrap()(data) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_client_name(ctx: *mut mpv_handle) -> *const c_char {
-    unsafe { pfn_mpv_client_name.unwrap()(ctx) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_free_node_contents(node: *mut mpv_node) {
-    unsafe { pfn_mpv_free_node_contents.unwrap()(node) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_command(ctx: *mut mpv_handle, args: *mut *const c_char) -> c_int {
-    unsafe { pfn_mpv_command.unwrap()(ctx, args) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_command_node(
-    ctx: *mut mpv_handle,
-    args: *mut mpv_node,
-    result: *mut mpv_node,
-) -> c_int {
-    unsafe { pfn_mpv_command_node.unwrap()(ctx, args, result) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_command_ret(
-    ctx: *mut mpv_handle,
-    args: *mut *const c_char,
-    result: *mut mpv_node,
-) -> c_int {
-    unsafe { pfn_mpv_command_ret.unwrap()(ctx, args, result) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_get_property(
-    ctx: *mut mpv_handle,
-    name: *const c_char,
-    format: mpv_format,
-    data: *mut c_void,
-) -> c_int {
-    unsafe { pfn_mpv_get_property.unwrap()(ctx, name, format, data) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_observe_property(
-    ctx: *mut mpv_handle,
-    reply_userdata: u64,
-    name: *const c_char,
-    format: mpv_format,
-) -> c_int {
-    unsafe { pfn_mpv_observe_property.unwrap()(ctx, reply_userdata, name, format) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_command_string(ctx: *mut mpv_handle, args: *const c_char) -> c_int {
-    unsafe { pfn_mpv_command_string.unwrap()(ctx, args) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_event_name(event: mpv_event_id) -> *const c_char {
-    unsafe { pfn_mpv_event_name.unwrap()(event) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_wait_event(ctx: *mut mpv_handle, timeout: f64) -> *mut mpv_event {
-    unsafe { pfn_mpv_wait_event.unwrap()(ctx, timeout) }
-}
-#[cfg(target_os = "windows")]
-pub unsafe fn mpv_wakeup(ctx: *mut mpv_handle) {
-    unsafe { pfn_mpv_wakeup.unwrap()(ctx) }
+        format: mpv_format
+    ) -> c_int = UNINITIALIZED;
+    pfn_mpv_command_string fn mpv_command_string(ctx: *mut mpv_handle, args: *const c_char) -> c_int =
+        UNINITIALIZED;
+    pfn_mpv_event_name fn mpv_event_name(event: mpv_event_id) -> *const c_char = null();
+    pfn_mpv_wait_event fn mpv_wait_event(ctx: *mut mpv_handle, timeout: f64) -> *mut mpv_event =
+        null_mut();
+    pfn_mpv_wakeup fn mpv_wakeup(ctx: *mut mpv_handle) = ();
 }
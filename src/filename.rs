@@ -0,0 +1,259 @@
+use regex::Regex;
+use std::{collections::HashMap, path::Path, sync::LazyLock};
+
+// most specific first
+static EPISODE: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+    [
+        // S01E05
+        r"(?i)\bS\d{1,2}\s?E(\d{1,3})\b",
+        // 第05话, 第5話, 第05集
+        r"第\s*(\d{1,3})\s*[话話集]",
+        // [Group] Title - 05 [1080p], Title - 05v2.mkv
+        r" - (\d{1,3})(?:v\d)?(?:\s|\[|\(|$)",
+        // [Group][Title][05][1080p]
+        r"\[(\d{1,3})(?:v\d)?\]",
+        // EP05, E05
+        r"(?i)\bEP?\s?(\d{1,3})\b",
+    ]
+    .iter()
+    .map(|pattern| Regex::new(pattern).unwrap())
+    .collect()
+});
+
+static SEASON: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+    [
+        // S02E05
+        r"(?i)\bS(\d{1,2})\s?E\d{1,3}\b",
+        // Season 2
+        r"(?i)\bSeason\s?(\d{1,2})\b",
+        // 2nd Season
+        r"(?i)\b(\d{1,2})(?:st|nd|rd|th)\s?Season\b",
+        // 第2季, 第二季
+        r"第\s*(\d{1,2}|[一二三四五六七八九十])\s*季",
+        // Title S2 - 05
+        r"(?i)\bS(\d{1,2})\b",
+    ]
+    .iter()
+    .map(|pattern| Regex::new(pattern).unwrap())
+    .collect()
+});
+
+static BRACKETS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[([^\]]*)\]|【([^】]*)】|\(([^)]*)\)").unwrap());
+
+// what a file name or media title tells about the episode
+#[derive(Debug, PartialEq)]
+pub struct Name {
+    pub title: String,
+    pub season: Option<usize>,
+    pub episode: Option<usize>,
+}
+
+// The file name without a directory or video extension.
+fn stem(path: &str) -> &str {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, extension))
+            if (2..=4).contains(&extension.len())
+                && extension.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            stem
+        }
+        _ => name,
+    }
+}
+
+fn first_number(patterns: &[Regex], text: &str) -> Option<usize> {
+    patterns
+        .iter()
+        .find_map(|pattern| number(&pattern.captures(text)?[1]))
+        .filter(|&number| number > 0)
+}
+
+// digits, or a Chinese numeral up to ten
+fn number(text: &str) -> Option<usize> {
+    text.parse().ok().or_else(|| {
+        "一二三四五六七八九十"
+            .chars()
+            .position(|c| text.starts_with(c))
+            .map(|n| n + 1)
+    })
+}
+
+// The episode number in the name of a video file, if it has one.
+pub fn episode_number(path: &str) -> Option<usize> {
+    first_number(&EPISODE, stem(path))
+}
+
+pub fn parse(path: &str) -> Name {
+    let stem = stem(path);
+    let episode = first_number(&EPISODE, stem);
+    let season = first_number(&SEASON, stem);
+
+    // the title comes before the episode and season
+    let end = EPISODE
+        .iter()
+        .chain(SEASON.iter())
+        .filter_map(|pattern| pattern.find(stem))
+        .map(|found| found.start())
+        .min()
+        .unwrap_or(stem.len());
+    // [Title][2nd Season][01], cut before the bracket the match is in
+    let head = &stem[..end];
+    let end = head
+        .rfind(['[', '【', '('])
+        .filter(|&open| !head[open..].contains([']', '】', ')']))
+        .unwrap_or(end);
+    let head = stem[..end].replace(['.', '_'], " ");
+    let mut title = BRACKETS.replace_all(&head, " ").to_string();
+    if title.trim().is_empty() {
+        // [Group][Title][05], the group comes first
+        title = BRACKETS
+            .captures_iter(&head)
+            .filter_map(|captures| captures.iter().skip(1).flatten().next())
+            .map(|group| group.as_str().to_string())
+            .filter(|group| !group.trim().is_empty())
+            .last()
+            .unwrap_or_default();
+    }
+    let title = title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches(['-', ' '])
+        .to_string();
+
+    Name {
+        title,
+        season,
+        episode,
+    }
+}
+
+// Dice coefficient of the character pairs of two titles, ignoring case,
+// spacing and punctuation. 1 for equal titles.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    fn pairs(title: &str) -> HashMap<(char, char), usize> {
+        let chars = title
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect::<Vec<_>>();
+        let mut pairs = HashMap::new();
+        if let [c] = chars[..] {
+            pairs.insert((c, c), 1);
+        }
+        for pair in chars.windows(2) {
+            *pairs.entry((pair[0], pair[1])).or_default() += 1;
+        }
+        pairs
+    }
+
+    let (a, b) = (pairs(a), pairs(b));
+    let total = a.values().sum::<usize>() + b.values().sum::<usize>();
+    if total == 0 {
+        return 0.;
+    }
+    let common = a
+        .iter()
+        .map(|(pair, &n)| n.min(b.get(pair).copied().unwrap_or(0)))
+        .sum::<usize>();
+    2. * common as f64 / total as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_file_names() {
+        // path, title, season, episode
+        let cases: &[(&str, &str, Option<usize>, Option<usize>)] = &[
+            (
+                "[Nekomoe kissaten][Oshi no Ko][2nd Season][01][1080p].mp4",
+                "Oshi no Ko",
+                Some(2),
+                Some(1),
+            ),
+            (
+                "[SubsPlease] Sousou no Frieren - 05 (1080p) [ABCD1234].mkv",
+                "Sousou no Frieren",
+                None,
+                Some(5),
+            ),
+            (
+                "/media/anime/[LoliHouse] Kusuriya no Hitorigoto - 12 [WebRip 1080p].mkv",
+                "Kusuriya no Hitorigoto",
+                None,
+                Some(12),
+            ),
+            (
+                "[Sakurato] Mushoku Tensei S2 - 03v2 [1080p].mkv",
+                "Mushoku Tensei",
+                Some(2),
+                Some(3),
+            ),
+            (
+                "Spy.x.Family.S02E05.1080p.WEB.mkv",
+                "Spy x Family",
+                Some(2),
+                Some(5),
+            ),
+            (
+                "Oshi no Ko 2nd Season - 01.mkv",
+                "Oshi no Ko",
+                Some(2),
+                Some(1),
+            ),
+            ("[Group][Title][05][1080p].mp4", "Title", None, Some(5)),
+            ("Title Season 2 EP07.mp4", "Title", Some(2), Some(7)),
+            ("葬送的芙莉莲 第05话.mp4", "葬送的芙莉莲", None, Some(5)),
+            (
+                "进击的巨人 第二季 第5集.mkv",
+                "进击的巨人",
+                Some(2),
+                Some(5),
+            ),
+            ("Title (2023) - 01.mkv", "Title", None, Some(1)),
+            ("Movie.mkv", "Movie", None, None),
+            ("Title.S00E00.mkv", "Title", None, None),
+        ];
+        for &(path, title, season, episode) in cases {
+            assert_eq!(
+                parse(path),
+                Name {
+                    title: title.to_string(),
+                    season,
+                    episode
+                },
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn finds_episode_numbers() {
+        let cases: &[(&str, Option<usize>)] = &[
+            ("/videos/Title - 05.mkv", Some(5)),
+            ("Title.S01E12.mkv", Some(12)),
+            ("[Group][Title][05v2].mp4", Some(5)),
+            ("Title 第100话.mp4", Some(100)),
+            ("Title 1080p.mkv", None),
+        ];
+        for &(path, episode) in cases {
+            assert_eq!(episode_number(path), episode, "{}", path);
+        }
+    }
+
+    #[test]
+    fn compares_titles() {
+        assert_eq!(similarity("Oshi no Ko", "oshi-no-ko"), 1.);
+        assert_eq!(similarity("", "Title"), 0.);
+        assert!(similarity("Sousou no Frieren", "Sousou no Frieren 2") > 0.9);
+        assert!(similarity("Sousou no Frieren", "Spy x Family") < 0.3);
+    }
+}
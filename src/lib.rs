@@ -1,26 +1,43 @@
+pub mod align;
+pub mod bilibili;
+pub mod blocklist;
+pub mod click;
+pub mod comments;
 pub mod dandanplay;
+pub mod density;
 pub mod emby;
 pub mod ffi;
+pub mod filename;
 pub mod log;
+pub mod menu;
+pub mod merge;
+pub mod metrics;
 pub mod mpv;
 pub mod options;
+pub mod render;
+pub mod store;
+pub mod track;
 pub mod utils;
 
 use crate::{
-    dandanplay::{Danmaku, Source, Status, StatusInner, get_danmaku},
+    click::Target,
+    comments::Comments,
+    dandanplay::{
+        Danmaku, fits_duration, forget_match, get_danmaku, refresh_danmaku, search_danmaku,
+    },
     ffi::{
         mpv_client_name, mpv_event_client_message, mpv_event_id, mpv_event_property, mpv_format,
         mpv_handle, mpv_node, mpv_observe_property, mpv_wait_event, mpv_wakeup,
     },
     log::{log_code, log_error},
     mpv::{get_property_f64, get_property_string, osd_message, osd_overlay, remove_overlay},
-    options::{Filter, Options},
+    options::{Filter, Options, Output},
+    render::{INTERVAL, Params, WINDOW},
+    track::Track,
 };
 use anyhow::anyhow;
 use mpv::expand_path;
-use rand::{Rng, rng};
 use std::{
-    collections::HashSet,
     ffi::CStr,
     fs,
     os::raw::c_int,
@@ -38,27 +55,26 @@ use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::fmt::time::ChronoUtc;
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
-const MAX_DURATION: f64 = 12.;
-const INTERVAL: f64 = 0.005;
-const MIN_STEP: f64 = INTERVAL / MAX_DURATION;
-const MAX_STEP: f64 = MIN_STEP * 1.3;
+const OVERLAY: i64 = 0;
 
 pub static mut CTX: *mut mpv_handle = null_mut();
 pub static CLIENT_NAME: OnceLock<String> = OnceLock::new();
 
 static ENABLED: AtomicBool = AtomicBool::new(false);
-static COMMENTS: LazyLock<Mutex<Option<Vec<Danmaku>>>> = LazyLock::new(|| Mutex::new(None));
-
-#[derive(Default, Clone, Copy)]
-struct Params {
-    delay: f64,
-    speed: f64,
-    osd_width: f64,
-    osd_height: f64,
-}
+static COMMENTS: LazyLock<Mutex<Option<Comments>>> = LazyLock::new(|| Mutex::new(None));
+// the episode whose cached comments are being refreshed, left behind when the
+// refresh is aborted so that it is tried again
+static REFRESHING: LazyLock<Mutex<Option<usize>>> = LazyLock::new(|| Mutex::new(None));
 
 #[unsafe(no_mangle)]
 extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> c_int {
+    let missing = ffi::missing();
+    if !missing.is_empty() {
+        panic!(
+            "danmaku: the program that loaded the plugin lacks {}",
+            missing.join(", ")
+        );
+    }
     unsafe {
         CTX = ctx;
         CLIENT_NAME.get_or_init(|| {
@@ -91,14 +107,13 @@ async fn main() -> c_int {
         }
     }
 
-    let options = *options::OPTIONS;
-
-    let filter = options::read_options()
+    let (conf, mut filter) = options::read_options()
         .map_err(|e| log_error(&e))
         .ok()
         .flatten()
-        .unwrap_or_default()
-        .1;
+        .unwrap_or_default();
+    options::set(conf.clone());
+    let mut options = conf;
 
     // Initialize tracing subscriber
     if ["true", "on", "enable"].contains(&options.log.to_ascii_lowercase().as_str()) {
@@ -131,35 +146,44 @@ async fn main() -> c_int {
     }
 
     let mut handle = spawn(async {});
-    let mut params = Params::default();
-    let mut pause = true;
+    let mut watcher = spawn(options::watch(options.reload_interval));
+    let mut script_opts = Vec::new();
+    let mut params = Params {
+        pause: true,
+        ..Default::default()
+    };
+    let mut track = Track::default();
     loop {
-        let timeout = if !pause && ENABLED.load(Ordering::SeqCst) {
-            INTERVAL
-        } else {
-            -1.
-        };
+        let timeout =
+            if !params.pause && ENABLED.load(Ordering::SeqCst) && options.output == Output::Overlay
+            {
+                INTERVAL
+            } else {
+                -1.
+            };
         let event = unsafe { &*mpv_wait_event(CTX, timeout) };
         match event.event_id {
             mpv_event_id::MPV_EVENT_SHUTDOWN => {
                 handle.abort();
+                watcher.abort();
                 return 0;
             }
             mpv_event_id::MPV_EVENT_FILE_LOADED => {
                 handle.abort();
+                menu::close().await;
                 *COMMENTS.lock().await = None;
+                *REFRESHING.lock().await = None;
                 params.delay = 0.;
+                track.forget();
                 if ENABLED.load(Ordering::SeqCst) {
-                    remove_overlay();
+                    remove_overlay(OVERLAY);
                     handle = spawn(get(filter.clone()));
                 }
             }
-            mpv_event_id::MPV_EVENT_PLAYBACK_RESTART => {
-                if ENABLED.load(Ordering::SeqCst) {
-                    if let Some(comments) = &mut *COMMENTS.lock().await {
-                        reset_status(comments);
-                        render(comments, params, options);
-                    }
+            mpv_event_id::MPV_EVENT_PLAYBACK_RESTART if ENABLED.load(Ordering::SeqCst) => {
+                if let Some(comments) = &mut *COMMENTS.lock().await {
+                    reset_status(comments, params, &options);
+                    render(comments, params, &options, &mut track);
                 }
             }
             mpv_event_id::MPV_EVENT_PROPERTY_CHANGE => 'a: {
@@ -169,7 +193,7 @@ async fn main() -> c_int {
                 }
                 let name = unsafe { CStr::from_ptr(data.name) };
                 if name == c"pause" {
-                    pause = unsafe { *(data.data as *mut c_int) } != 0;
+                    params.pause = unsafe { *(data.data as *mut c_int) } != 0;
                 } else if name == c"osd-width" {
                     params.osd_width = unsafe { *(data.data as *mut f64) };
                 } else if name == c"osd-height" {
@@ -177,67 +201,42 @@ async fn main() -> c_int {
                 } else if name == c"script-opts" {
                     let data = unsafe { &*(data.data as *mut mpv_node) };
                     assert_eq!(data.format, mpv_format::MPV_FORMAT_NODE_MAP);
-                    let list = unsafe { &*data.u.list };
-                    if list.num == 0 {
+                    script_opts = read_script_opts(data);
+
+                    let runtime = runtime_options(&script_opts);
+                    let sources = script_opts
+                        .iter()
+                        .find(|(key, value)| key == "filter_source" && !value.is_empty())
+                        .map(|(_, value)| options::parse_sources(value));
+                    let keywords = script_opts
+                        .iter()
+                        .find(|(key, value)| key == "filter" && !value.is_empty())
+                        .map(|(_, value)| options::parse_keywords(value));
+
+                    let sources_changed = *filter.sources_rt.lock().await != sources;
+                    let keywords_changed = *filter.keywords_rt.lock().await != keywords;
+                    if sources_changed {
+                        osd_message(&format!(
+                            "Danmaku: blocked danmaku from {:?}",
+                            sources.as_ref().unwrap_or(&filter.sources)
+                        ));
+                        *filter.sources_rt.lock().await = sources;
+                    }
+                    if keywords_changed {
+                        *filter.keywords_rt.lock().await = keywords;
+                    }
+                    if !sources_changed && !keywords_changed && runtime == options {
                         break 'a;
                     }
-                    let num = list.num.try_into().unwrap();
-                    let keys = unsafe { from_raw_parts(list.keys, num) };
-                    let values = unsafe { from_raw_parts(list.values, num) };
-                    for (key, value) in keys.iter().zip(values) {
-                        if unsafe { CStr::from_ptr(key.cast()) }
-                            .to_str()
-                            .is_ok_and(|key| {
-                                key == format!(
-                                    "{}-filter_source",
-                                    CLIENT_NAME.get().unwrap_or(&"".to_string())
-                                )
-                            })
-                        {
-                            assert_eq!(value.format, mpv_format::MPV_FORMAT_STRING);
-                            match unsafe { CStr::from_ptr(value.u.string) }.to_str() {
-                                Ok(value) => {
-                                    *filter.sources_rt.lock().await = if value.is_empty() {
-                                        if let Some(comments) = &mut *COMMENTS.lock().await {
-                                            for comment in comments.iter_mut() {
-                                                comment.blocked =
-                                                    filter.sources.contains(&comment.source);
-                                                comment.status = Status::Uninitialized;
-                                            }
-                                            if ENABLED.load(Ordering::SeqCst) {
-                                                render(comments, params, options);
-                                            }
-                                        }
-                                        osd_message(&format!(
-                                            "Danmaku: blocked danmaku from {:?}",
-                                            filter.sources
-                                        ));
-                                        None
-                                    } else {
-                                        let sources = value
-                                            .split(',')
-                                            .map(Into::into)
-                                            .filter(|&s| s != Source::Unknown)
-                                            .collect::<HashSet<_>>();
-                                        if let Some(comments) = &mut *COMMENTS.lock().await {
-                                            for comment in comments.iter_mut() {
-                                                comment.blocked = sources.contains(&comment.source);
-                                                comment.status = Status::Uninitialized;
-                                            }
-                                            if ENABLED.load(Ordering::SeqCst) {
-                                                render(comments, params, options);
-                                            }
-                                        }
-                                        osd_message(&format!(
-                                            "Danmaku: blocked danmaku from {:?}",
-                                            sources
-                                        ));
-                                        Some(sources)
-                                    }
-                                }
-                                Err(error) => log_error(&error.into()),
-                            }
-                            break;
+                    options = runtime;
+
+                    if let Some(comments) = &mut *COMMENTS.lock().await {
+                        if sources_changed || keywords_changed {
+                            refilter(comments, &filter).await;
+                        }
+                        reset_status(comments, params, &options);
+                        if ENABLED.load(Ordering::SeqCst) {
+                            render(comments, params, &options, &mut track);
                         }
                     }
                 } else if name == c"speed" {
@@ -256,14 +255,24 @@ async fn main() -> c_int {
                     if arg1 == c"toggle-danmaku" {
                         if ENABLED.fetch_not(Ordering::SeqCst) {
                             handle.abort();
-                            remove_overlay();
+                            menu::close().await;
+                            click::bind(false);
+                            remove_overlay(OVERLAY);
+                            track.remove();
                             osd_message("Danmaku: off");
                         } else {
+                            click::bind(options.click_menu);
                             match &mut *COMMENTS.lock().await {
                                 Some(comments) => {
-                                    reset_status(comments);
-                                    render(comments, params, options);
-                                    loaded(comments.iter().filter(|c| !c.blocked).count());
+                                    reset_status(comments, params, &options);
+                                    render(comments, params, &options, &mut track);
+                                    loaded(
+                                        comments.len(),
+                                        comments.danmaku.last().map(|comment| comment.time),
+                                    );
+                                    if let Some(episode_id) = *REFRESHING.lock().await {
+                                        handle = spawn(retry_refresh(episode_id, filter.clone()));
+                                    }
                                 }
                                 None => {
                                     handle = spawn(get(filter.clone()));
@@ -271,6 +280,186 @@ async fn main() -> c_int {
                                 }
                             }
                         }
+                    } else if arg1 == c"danmaku-search" {
+                        let keyword = args
+                            .iter()
+                            .filter_map(|&arg| unsafe { CStr::from_ptr(arg) }.to_str().ok())
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        if keyword.is_empty() {
+                            log_error(&anyhow!(
+                                "command danmaku-search: required argument keyword not set"
+                            ));
+                            break 'a;
+                        }
+                        handle.abort();
+                        menu::close().await;
+                        ENABLED.store(true, Ordering::SeqCst);
+                        click::bind(options.click_menu);
+                        osd_message(&format!("Danmaku: searching for {}", keyword));
+                        handle = spawn(search(keyword, filter.clone()));
+                    } else if arg1 == c"danmaku-click" {
+                        let target = COMMENTS.lock().await.as_ref().and_then(|comments| {
+                            let x = get_property_f64(c"mouse-pos/x")?;
+                            let y = get_property_f64(c"mouse-pos/y")?;
+                            let pos = get_property_f64(c"time-pos")?;
+                            // from OSD pixels to the frame comments are drawn in
+                            let (width, height) = comments.frame;
+                            comments
+                                .hit(
+                                    x / params.osd_width * width,
+                                    y / params.osd_height * height,
+                                    pos,
+                                )
+                                .map(Target::from)
+                        });
+                        if let Some(target) = target {
+                            menu::close().await;
+                            spawn(click::act(target));
+                        }
+                    } else if arg1 == c"danmaku-menu" {
+                        if let Some(action) = args
+                            .first()
+                            .and_then(|&action| unsafe { CStr::from_ptr(action) }.to_str().ok())
+                        {
+                            menu::handle(action).await;
+                        }
+                    } else if arg1 == c"danmaku-reload-config" {
+                        let (conf, new_filter) = match options::read_options() {
+                            Ok(conf) => conf.unwrap_or_default(),
+                            Err(error) => {
+                                log_error(&error);
+                                osd_message(&format!(
+                                    "Danmaku: failed to reload config: {}",
+                                    error
+                                ));
+                                break 'a;
+                            }
+                        };
+                        let changes =
+                            options::changes((&options::get(), &filter), (&conf, &new_filter));
+                        *new_filter.sources_rt.lock().await =
+                            filter.sources_rt.lock().await.clone();
+                        *new_filter.keywords_rt.lock().await =
+                            filter.keywords_rt.lock().await.clone();
+                        if conf.reload_interval != options::get().reload_interval {
+                            watcher.abort();
+                            watcher = spawn(options::watch(conf.reload_interval));
+                        }
+                        options::set(conf);
+                        filter = new_filter;
+                        options = runtime_options(&script_opts);
+                        if ENABLED.load(Ordering::SeqCst) {
+                            click::bind(options.click_menu);
+                        }
+
+                        if let Some(comments) = &mut *COMMENTS.lock().await {
+                            refilter(comments, &filter).await;
+                            reset_status(comments, params, &options);
+                            if ENABLED.load(Ordering::SeqCst) {
+                                render(comments, params, &options, &mut track);
+                            }
+                        }
+                        if changes.is_empty() {
+                            osd_message("Danmaku: config reloaded, nothing changed");
+                        } else {
+                            osd_message(&format!(
+                                "Danmaku: config reloaded\n{}",
+                                changes.join("\n")
+                            ));
+                        }
+                    } else if arg1 == c"danmaku-block-user" || arg1 == c"danmaku-block-keyword" {
+                        let by_user = arg1 == c"danmaku-block-user";
+                        let (list, kind) = if by_user {
+                            (&blocklist::USERS, "user")
+                        } else {
+                            (&blocklist::KEYWORDS, "keyword")
+                        };
+                        let mut comments = COMMENTS.lock().await;
+                        // without an argument, the latest comment on screen
+                        let target = match args.first() {
+                            Some(&entry) => unsafe { CStr::from_ptr(entry) }
+                                .to_str()
+                                .ok()
+                                .map(|entry| (entry.to_string(), None)),
+                            None => comments
+                                .as_ref()
+                                .zip(get_property_f64(c"time-pos"))
+                                .and_then(|(comments, pos)| {
+                                    comments.latest_shown(pos - params.delay)
+                                })
+                                .map(|comment| {
+                                    if by_user {
+                                        (comment.user.clone(), Some(comment.message.clone()))
+                                    } else {
+                                        (comment.message.clone(), None)
+                                    }
+                                }),
+                        };
+                        let Some((entry, message)) = target.filter(|(entry, _)| !entry.is_empty())
+                        else {
+                            osd_message(&format!("Danmaku: no {} to block", kind));
+                            break 'a;
+                        };
+                        match list.add(&entry) {
+                            Ok(true) => {
+                                if let Some(comments) = &mut *comments {
+                                    refilter(comments, &filter).await;
+                                    reset_status(comments, params, &options);
+                                    if ENABLED.load(Ordering::SeqCst) {
+                                        render(comments, params, &options, &mut track);
+                                    }
+                                }
+                                osd_message(&match message {
+                                    Some(message) => {
+                                        format!("Danmaku: blocked {} {}: {}", kind, entry, message)
+                                    }
+                                    None => format!("Danmaku: blocked {} {}", kind, entry),
+                                });
+                            }
+                            Ok(false) => osd_message(&format!(
+                                "Danmaku: {} {} is already blocked",
+                                kind, entry
+                            )),
+                            Err(error) => log_error(&error),
+                        }
+                    } else if arg1 == c"danmaku-unblock-user" || arg1 == c"danmaku-unblock-keyword"
+                    {
+                        let (list, kind) = if arg1 == c"danmaku-unblock-user" {
+                            (&blocklist::USERS, "user")
+                        } else {
+                            (&blocklist::KEYWORDS, "keyword")
+                        };
+                        // without an argument, the one blocked last
+                        let Some(entry) = args
+                            .first()
+                            .and_then(|&entry| unsafe { CStr::from_ptr(entry) }.to_str().ok())
+                            .map(String::from)
+                            .or_else(|| list.last())
+                        else {
+                            osd_message(&format!("Danmaku: no {} to unblock", kind));
+                            break 'a;
+                        };
+                        match list.remove(&entry) {
+                            Ok(true) => {
+                                if let Some(comments) = &mut *COMMENTS.lock().await {
+                                    refilter(comments, &filter).await;
+                                    reset_status(comments, params, &options);
+                                    if ENABLED.load(Ordering::SeqCst) {
+                                        render(comments, params, &options, &mut track);
+                                    }
+                                }
+                                osd_message(&format!("Danmaku: unblocked {} {}", kind, entry));
+                            }
+                            Ok(false) => {
+                                osd_message(&format!("Danmaku: {} {} is not blocked", kind, entry))
+                            }
+                            Err(error) => log_error(&error),
+                        }
+                    } else if arg1 == c"danmaku-forget-match" {
+                        if let Some(path) = get_property_string(c"path") {
+                            spawn(forget(path));
+                        }
                     } else if arg1 == c"danmaku-delay" {
                         match args.first() {
                             Some(&seconds) => {
@@ -283,8 +472,8 @@ async fn main() -> c_int {
                                         params.delay += seconds;
                                         if ENABLED.load(Ordering::SeqCst) {
                                             if let Some(comments) = &mut *COMMENTS.lock().await {
-                                                reset_status(comments);
-                                                render(comments, params, options);
+                                                reset_status(comments, params, &options);
+                                                render(comments, params, &options, &mut track);
                                             }
                                         }
                                         osd_message(&format!(
@@ -306,7 +495,7 @@ async fn main() -> c_int {
             }
             mpv_event_id::MPV_EVENT_NONE => {
                 if let Some(comments) = &mut *COMMENTS.lock().await {
-                    render(comments, params, options);
+                    render(comments, params, &options, &mut track);
                 }
             }
             _ => (),
@@ -314,139 +503,198 @@ async fn main() -> c_int {
     }
 }
 
-#[derive(Clone, Copy)]
-struct Row {
-    end: f64,
-    step: f64,
-}
+fn render(comments: &mut Comments, params: Params, options: &Options, track: &mut Track) {
+    if options.output == Output::Track {
+        // subtitles are laid out over the video rather than the window
+        let params = match (
+            get_property_f64(c"video-params/dw"),
+            get_property_f64(c"video-params/dh"),
+        ) {
+            (Some(width), Some(height)) => Params {
+                osd_width: width,
+                osd_height: height,
+                ..params
+            },
+            _ => params,
+        };
+        if track.update(comments, params, options) {
+            remove_overlay(OVERLAY);
+        }
+        return;
+    }
+    track.remove();
 
-fn render(comments: &mut [Danmaku], params: Params, options: Options) {
     let Some(pos) = get_property_f64(c"time-pos") else {
         return;
     };
-    let mut width = 1920.;
-    let mut height = 1080.;
-    let ratio = params.osd_width / params.osd_height;
-    if width / height < ratio {
-        height = width / ratio;
-    } else if width / height > ratio {
-        width = height * ratio;
-    }
-    let spacing = options.font_size / 10.;
-    let mut rows = vec![
-        Row {
-            end: 0.,
-            step: MIN_STEP,
-        };
-        ((height * (1. - options.reserved_space) / (options.font_size + spacing))
-            as usize)
-            .max(1)
-    ];
-
-    let mut danmaku = Vec::new();
-    let mut rng = rng();
-    'it: for comment in comments.iter_mut().filter(|c| !c.blocked) {
-        let time = comment.time + params.delay;
-        if time > pos {
-            break;
-        }
+    let frame = render::layout(comments, pos, params, options);
+    let events = frame
+        .events
+        .iter()
+        .map(|event| event.text.as_str())
+        .collect::<Vec<_>>();
+    osd_overlay(
+        OVERLAY,
+        &events.join("\n"),
+        frame.width as i64,
+        frame.height as i64,
+    );
+}
 
-        let status = match &mut comment.status {
-            Status::Status(status) => status,
-            Status::Overlapping => continue,
-            Status::Uninitialized => 'status: {
-                let ticks = (pos - time) / INTERVAL;
-                for (row, status) in rows.iter().enumerate() {
-                    if status.end < width - width * ticks * MIN_STEP {
-                        let max_step = if status.end == 0. {
-                            MAX_STEP
-                        } else {
-                            // 1 / max_step - ticks = status.end / width / status.step
-                            let max_step = 1. / (ticks + status.end / width / status.step);
-                            max_step.min(MAX_STEP)
-                        };
-                        let step = rng.random_range(MIN_STEP..max_step);
-                        let x = width - width * ticks * step;
-                        break 'status comment.status.insert(StatusInner { x, row, step });
-                    }
-                }
-                if options.no_overlap {
-                    comment.status = Status::Overlapping;
-                    continue 'it;
-                }
-                let row = rows
-                    .iter()
-                    .enumerate()
-                    .min_by(|a, b| a.1.end.partial_cmp(&b.1.end).unwrap())
-                    .map(|(row, _)| row)
-                    .unwrap();
-                let step = MIN_STEP;
-                let x = width - width * ticks * step;
-                comment.status.insert(StatusInner { x, row, step })
-            }
-        };
-        if status.x + comment.count as f64 * options.font_size + spacing <= 0. {
-            continue;
-        }
-        danmaku.push(format!(
-            "{{\\pos({},{})\\c&H{:x}{:x}{:x}&\\alpha&H{:x}\\fs{}\\bord1.5\\shad0\\b1\\q2}}{}",
-            status.x,
-            status.row as f64 * (options.font_size + spacing),
-            comment.b,
-            comment.g,
-            comment.r,
-            options.transparency,
-            options.font_size,
-            comment.message
-        ));
-
-        status.x -= width * status.step * params.speed * options.speed;
-        if let Some(row) = rows.get_mut(status.row) {
-            let end = status.x + comment.count as f64 * options.font_size + spacing;
-            if end / status.step > row.end / row.step {
-                *row = Row {
-                    end,
-                    step: status.step,
-                };
-            }
-        }
+async fn get(filter: Arc<Filter>) {
+    let Some(path) = get_property_string(c"path") else {
+        return;
+    };
+
+    if let Some(episode_id) = load(get_danmaku(&path, filter.clone()).await).await {
+        refresh(&path, episode_id, filter).await;
     }
-    osd_overlay(&danmaku.join("\n"), width as i64, height as i64);
 }
 
-async fn get(filter: Arc<Filter>) {
+async fn search(keyword: String, filter: Arc<Filter>) {
     let Some(path) = get_property_string(c"path") else {
         return;
     };
 
-    match get_danmaku(&path, filter).await {
-        Ok(danmaku) => {
-            let n = danmaku.iter().filter(|c| !c.blocked).count();
-            *COMMENTS.lock().await = Some(danmaku);
+    if let Some(episode_id) = load(search_danmaku(&path, &keyword, filter.clone()).await).await {
+        refresh(&path, episode_id, filter).await;
+    }
+}
+
+// Returns the episode whose cached comments should be refreshed.
+async fn load(danmaku: anyhow::Result<(Vec<Danmaku>, Option<usize>)>) -> Option<usize> {
+    match danmaku {
+        Ok((danmaku, stale)) => {
+            let comments = Comments::new(danmaku);
+            let n = comments.len();
+            let last = comments.danmaku.last().map(|comment| comment.time);
+            *COMMENTS.lock().await = Some(comments);
+            *REFRESHING.lock().await = None;
             if ENABLED.load(Ordering::SeqCst) {
                 unsafe { mpv_wakeup(CTX) };
-                loaded(n);
+                loaded(n, last);
             }
+            stale
         }
         Err(error) => {
             log_error(&error);
             if ENABLED.load(Ordering::SeqCst) {
                 osd_message(&format!("Danmaku: {}", error));
             }
+            None
         }
     }
 }
 
-fn reset_status(comments: &mut [Danmaku]) {
-    for comment in comments {
-        comment.status = Status::Uninitialized;
+async fn refresh(path: &str, episode_id: usize, filter: Arc<Filter>) {
+    *REFRESHING.lock().await = Some(episode_id);
+    match refresh_danmaku(path, episode_id, filter).await {
+        Ok((danmaku, refreshed)) => {
+            if let Some(danmaku) = danmaku {
+                let comments = Comments::new(danmaku);
+                let n = comments.len();
+                *COMMENTS.lock().await = Some(comments);
+                if ENABLED.load(Ordering::SeqCst) {
+                    unsafe { mpv_wakeup(CTX) };
+                    osd_message(&format!("Danmaku: refreshed, {} comments", n));
+                }
+            }
+            if let Err(error) = refreshed.save().await {
+                log_error(&error);
+            }
+        }
+        Err(error) => log_error(&error),
+    }
+    *REFRESHING.lock().await = None;
+}
+
+async fn retry_refresh(episode_id: usize, filter: Arc<Filter>) {
+    if let Some(path) = get_property_string(c"path") {
+        refresh(&path, episode_id, filter).await;
+    }
+}
+
+async fn forget(path: String) {
+    match forget_match(&path).await {
+        Ok(true) => {
+            osd_message("Danmaku: forgot the matched episode, use danmaku-search to pick one")
+        }
+        Ok(false) => osd_message("Danmaku: no episode is remembered for this file"),
+        Err(error) => {
+            log_error(&error);
+            osd_message(&format!("Danmaku: {}", error));
+        }
+    }
+}
+
+// The entries of script-opts meant for this script, without the prefix.
+fn read_script_opts(data: &mpv_node) -> Vec<(String, String)> {
+    let list = unsafe { &*data.u.list };
+    if list.num == 0 {
+        return Vec::new();
+    }
+    let num = list.num.try_into().unwrap();
+    let keys = unsafe { from_raw_parts(list.keys, num) };
+    let values = unsafe { from_raw_parts(list.values, num) };
+    let prefix = format!("{}-", CLIENT_NAME.get().unwrap_or(&"".to_string()));
+    keys.iter()
+        .zip(values)
+        .filter_map(|(key, value)| {
+            let key = unsafe { CStr::from_ptr(key.cast()) }.to_str().ok()?;
+            let key = key.strip_prefix(&prefix)?;
+            if value.format != mpv_format::MPV_FORMAT_STRING {
+                return None;
+            }
+            match unsafe { CStr::from_ptr(value.u.string) }.to_str() {
+                Ok(value) => Some((key.to_string(), value.to_string())),
+                Err(error) => {
+                    log_error(&error.into());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+async fn refilter(comments: &mut Comments, filter: &Filter) {
+    filter.apply(&mut comments.danmaku).await;
+    comments.reindex();
+}
+
+// The conf file options with the rendering options from script-opts applied.
+fn runtime_options(script_opts: &[(String, String)]) -> Options {
+    let mut runtime = options::get();
+    for (key, value) in script_opts {
+        options::set_render_option(&mut runtime, key, value);
     }
+    runtime
+}
+
+fn reset_status(comments: &mut Comments, params: Params, options: &Options) {
+    let pos = get_property_f64(c"time-pos").unwrap_or_default();
+    comments.reset(pos - params.delay - WINDOW / options.speed);
 }
 
-fn loaded(n: usize) {
-    osd_message(&format!(
+// `last` is the time of the last comment.
+fn loaded(n: usize, last: Option<f64>) {
+    let mut message = format!(
         "Loaded {} danmaku comment{}",
         n,
         if n > 1 { "s" } else { "" }
-    ));
+    );
+    // comments far past the end of the video are likely of another episode
+    if let (Some(last), Some(duration)) = (last, get_property_f64(c"duration")) {
+        if !fits_duration(last, duration) {
+            message.push_str(&format!(
+                "\nDanmaku: comments run until {}, past the end of the video at {}, they may be of another episode",
+                clock(last),
+                clock(duration)
+            ));
+        }
+    }
+    osd_message(&message);
+}
+
+fn clock(seconds: f64) -> String {
+    format!("{}:{:02}", (seconds / 60.) as u64, (seconds % 60.) as u64)
 }
@@ -0,0 +1,169 @@
+use crate::{
+    CLIENT_NAME,
+    mpv::{command, osd_overlay, remove_overlay},
+};
+use anyhow::{Result, anyhow};
+use std::sync::LazyLock;
+use tokio::sync::{Mutex, oneshot};
+
+const OVERLAY: i64 = 1;
+const SECTION: &str = "danmaku-menu";
+// number of items shown around the selection
+const PAGE: usize = 10;
+
+struct Menu {
+    title: String,
+    items: Vec<String>,
+    selected: usize,
+    sender: oneshot::Sender<usize>,
+}
+
+static MENU: LazyLock<Mutex<Option<Menu>>> = LazyLock::new(|| Mutex::new(None));
+
+// Shows `items` on the OSD and waits for the user to pick one of them with
+// the `danmaku-menu` script-message, which is bound to the arrow keys, ENTER,
+// ESC and the digits while the menu is open.
+pub async fn choose(title: &str, items: Vec<String>) -> Result<usize> {
+    if items.is_empty() {
+        return Err(anyhow!("nothing to choose from"));
+    }
+    let (sender, receiver) = oneshot::channel();
+    {
+        let mut menu = MENU.lock().await;
+        *menu = Some(Menu {
+            title: title.to_string(),
+            items,
+            selected: 0,
+            sender,
+        });
+        bind();
+        render(menu.as_ref().unwrap());
+    }
+    receiver.await.map_err(|_| anyhow!("selection cancelled"))
+}
+
+pub async fn handle(action: &str) {
+    let mut guard = MENU.lock().await;
+    let Some(menu) = guard.as_mut() else {
+        return;
+    };
+    let last = menu.items.len() - 1;
+    let chosen = match action {
+        "up" => {
+            menu.selected = menu.selected.checked_sub(1).unwrap_or(last);
+            None
+        }
+        "down" => {
+            menu.selected = if menu.selected == last {
+                0
+            } else {
+                menu.selected + 1
+            };
+            None
+        }
+        "page-up" => {
+            menu.selected = menu.selected.saturating_sub(PAGE);
+            None
+        }
+        "page-down" => {
+            menu.selected = (menu.selected + PAGE).min(last);
+            None
+        }
+        "select" => Some(menu.selected),
+        "cancel" => {
+            // dropping the sender cancels the selection
+            *guard = None;
+            unbind();
+            return;
+        }
+        digit => match digit.parse::<usize>() {
+            Ok(n) if (1..=last + 1).contains(&n) => Some(n - 1),
+            _ => return,
+        },
+    };
+    match chosen {
+        Some(index) => {
+            if let Some(menu) = guard.take() {
+                _ = menu.sender.send(index);
+            }
+            unbind();
+        }
+        None => render(menu),
+    }
+}
+
+pub async fn close() {
+    if MENU.lock().await.take().is_some() {
+        unbind();
+    }
+}
+
+fn render(menu: &Menu) {
+    let first = menu
+        .selected
+        .saturating_sub(PAGE / 2)
+        .min(menu.items.len().saturating_sub(PAGE));
+    let mut lines = vec![format!("{{\\b1}}{}{{\\b0}}", escape(&menu.title))];
+    if first > 0 {
+        lines.push("...".to_string());
+    }
+    for (i, item) in menu.items.iter().enumerate().skip(first).take(PAGE) {
+        let item = format!("{}. {}", i + 1, escape(item));
+        lines.push(if i == menu.selected {
+            format!("{{\\c&H00FFFF&}}> {}{{\\c&HFFFFFF&}}", item)
+        } else {
+            format!("   {}", item)
+        });
+    }
+    if first + PAGE < menu.items.len() {
+        lines.push("...".to_string());
+    }
+    osd_overlay(
+        OVERLAY,
+        &format!(
+            "{{\\an7\\pos(20,20)\\fs24\\bord2\\q2}}{}",
+            lines.join("\\N")
+        ),
+        0,
+        720,
+    );
+}
+
+fn bind() {
+    let client = CLIENT_NAME.get().map_or("", |name| name.as_str());
+    let contents = [
+        ("UP", "up"),
+        ("DOWN", "down"),
+        ("WHEEL_UP", "up"),
+        ("WHEEL_DOWN", "down"),
+        ("PGUP", "page-up"),
+        ("PGDWN", "page-down"),
+        ("ENTER", "select"),
+        ("KP_ENTER", "select"),
+        ("ESC", "cancel"),
+        ("1", "1"),
+        ("2", "2"),
+        ("3", "3"),
+        ("4", "4"),
+        ("5", "5"),
+        ("6", "6"),
+        ("7", "7"),
+        ("8", "8"),
+        ("9", "9"),
+    ]
+    .map(|(key, action)| format!("{key} script-message-to {client} danmaku-menu {action}"))
+    .join("\n");
+    command(&["define-section", SECTION, &contents, "force"]);
+    command(&["enable-section", SECTION]);
+}
+
+fn unbind() {
+    command(&["disable-section", SECTION]);
+    remove_overlay(OVERLAY);
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\u{200b}")
+        .replace('{', "\\{")
+        .replace('\n', " ")
+}
@@ -0,0 +1,129 @@
+use crate::{dandanplay::Danmaku, options::Options};
+use std::collections::HashMap;
+
+// font size of a merged comment grows with the number of comments it stands
+// for, up to this factor
+const MAX_GROWTH: f64 = 1.6;
+
+// How much larger a comment standing for `merged` comments is shown.
+pub fn growth(merged: usize) -> f64 {
+    (1. + 0.15 * (merged as f64).log2()).min(MAX_GROWTH)
+}
+
+// Blocks comments with the same text sent within `merge_window` seconds of
+// the first of them, which is then shown suffixed with "×N". Comments that
+// are already blocked are left alone, so that this is done again whenever
+// what is blocked changes. `danmaku` must be sorted by time.
+pub fn merge_duplicates(danmaku: &mut [Danmaku], options: &Options) {
+    for comment in danmaku.iter_mut() {
+        comment.merged = 1;
+    }
+    if options.merge_window <= 0. {
+        return;
+    }
+
+    // normalized text -> position of the comment starting the current group
+    let mut groups: HashMap<_, usize> = HashMap::new();
+    for i in 0..danmaku.len() {
+        if danmaku[i].blocked {
+            continue;
+        }
+        let key = (danmaku[i].mode, normalize(&danmaku[i].message, options));
+        match groups.get(&key) {
+            Some(&head) if danmaku[i].time - danmaku[head].time <= options.merge_window => {
+                danmaku[head].merged += 1;
+                danmaku[i].blocked = true;
+            }
+            _ => {
+                groups.insert(key, i);
+            }
+        }
+    }
+}
+
+fn normalize(message: &str, options: &Options) -> String {
+    let mut text = String::with_capacity(message.len());
+    for c in message.trim().chars() {
+        let c = if options.merge_fold_width {
+            fold_width(c)
+        } else {
+            c
+        };
+        if options.merge_collapse_repeats && text.ends_with(c) {
+            continue;
+        }
+        text.push(c);
+    }
+    text
+}
+
+// full-width ASCII to ASCII, lower case
+fn fold_width(c: char) -> char {
+    let c = match c {
+        '\u{3000}' => ' ',
+        '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+        _ => c,
+    };
+    c.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dandanplay::{Mode, Source};
+
+    fn comment(message: &str, time: f64, user: &str) -> Danmaku {
+        Danmaku::new(
+            message,
+            time,
+            Mode::Scroll,
+            1.,
+            0xffffff,
+            Source::Dandan,
+            user,
+        )
+    }
+
+    #[test]
+    fn merges_again_when_blocking_changes() {
+        let options = Options {
+            merge_window: 5.,
+            ..Options::default()
+        };
+        let mut danmaku = vec![
+            comment("233", 1., "a"),
+            comment("2333", 2., "b"),
+            comment("hello", 3., "c"),
+            comment("２３３", 4., "c"),
+            comment("233", 7., "d"),
+        ];
+        merge_duplicates(&mut danmaku, &options);
+        let merged = |danmaku: &[Danmaku]| {
+            danmaku
+                .iter()
+                .filter(|comment| !comment.blocked)
+                .map(|comment| (comment.text().into_owned(), comment.merged))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            merged(&danmaku),
+            [
+                ("233 ×3".to_string(), 3),
+                ("hello".to_string(), 1),
+                ("233".to_string(), 1)
+            ]
+        );
+        assert_eq!(danmaku[0].message, "233");
+
+        // the first comment of the group is blocked afterwards, as the filter
+        // does before merging
+        for comment in danmaku.iter_mut() {
+            comment.blocked = comment.user == "a";
+        }
+        merge_duplicates(&mut danmaku, &options);
+        assert_eq!(
+            merged(&danmaku),
+            [("2333 ×3".to_string(), 3), ("hello".to_string(), 1)]
+        );
+    }
+}
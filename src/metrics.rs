@@ -0,0 +1,63 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Advance widths of the East Asian width classes, in units of the font size.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Calibration {
+    // Latin letters, digits, half-width kana
+    pub narrow: f64,
+    // CJK ideographs, kana, full-width forms
+    pub wide: f64,
+    pub emoji: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            narrow: 0.55,
+            wide: 1.,
+            emoji: 1.15,
+        }
+    }
+}
+
+impl Calibration {
+    // narrow:0.55,wide:1,emoji:1.15 where every entry is optional
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut calibration = Self::default();
+        for entry in value.split(',').filter(|entry| !entry.is_empty()) {
+            let (class, width) = entry.split_once(':')?;
+            let width = width.trim().parse().ok().filter(|&w: &f64| w > 0.)?;
+            match class.trim() {
+                "narrow" => calibration.narrow = width,
+                "wide" => calibration.wide = width,
+                "emoji" => calibration.emoji = width,
+                _ => return None,
+            }
+        }
+        Some(calibration)
+    }
+}
+
+// Estimated width of `text` in units of the font size.
+pub fn width(text: &str, calibration: &Calibration) -> f64 {
+    text.graphemes(true)
+        .map(|grapheme| advance(grapheme, calibration))
+        .sum()
+}
+
+fn advance(grapheme: &str, calibration: &Calibration) -> f64 {
+    match grapheme.width() {
+        0 => 0.,
+        1 => calibration.narrow,
+        _ if is_emoji(grapheme) => calibration.emoji,
+        _ => calibration.wide,
+    }
+}
+
+// pictographs live above the BMP, or ask for emoji presentation
+fn is_emoji(grapheme: &str) -> bool {
+    grapheme
+        .chars()
+        .any(|c| c >= '\u{1f000}' || c == '\u{fe0f}')
+}
@@ -16,7 +16,7 @@ use std::{
     ptr::{addr_of_mut, null, null_mut},
 };
 
-pub fn osd_overlay(data: &str, width: i64, height: i64) {
+pub fn osd_overlay(id: i64, data: &str, width: i64, height: i64) {
     let mut keys = [c"name", c"id", c"format", c"data", c"res_x", c"res_y"]
         .map(|key| CString::from(key).into_raw());
     let value1 = CString::from(c"osd-overlay").into_raw();
@@ -29,7 +29,7 @@ pub fn osd_overlay(data: &str, width: i64, height: i64) {
         },
         mpv_node {
             format: mpv_format::MPV_FORMAT_INT64,
-            u: u { int64: 0 },
+            u: u { int64: id },
         },
         mpv_node {
             format: mpv_format::MPV_FORMAT_STRING,
@@ -74,7 +74,7 @@ pub fn osd_overlay(data: &str, width: i64, height: i64) {
     }
 }
 
-pub fn remove_overlay() {
+pub fn remove_overlay(id: i64) {
     let mut keys = [c"name", c"id", c"format", c"data"].map(|key| CString::from(key).into_raw());
     let value1 = CString::from(c"osd-overlay").into_raw();
     let value3 = CString::from(c"none").into_raw();
@@ -86,7 +86,7 @@ pub fn remove_overlay() {
         },
         mpv_node {
             format: mpv_format::MPV_FORMAT_INT64,
-            u: u { int64: 0 },
+            u: u { int64: id },
         },
         mpv_node {
             format: mpv_format::MPV_FORMAT_STRING,
@@ -165,6 +165,9 @@ pub fn get_property_string(name: &CStr) -> Option<String> {
 }
 
 pub fn expand_path(path: &str) -> Result<String> {
+    if unsafe { CTX.is_null() } {
+        return expand_path_headless(path);
+    }
     unsafe {
         let arg2 = CString::new(path).unwrap();
         let mut args = [c"expand-path".as_ptr(), arg2.as_ptr(), null()];
@@ -186,6 +189,45 @@ pub fn expand_path(path: &str) -> Result<String> {
     }
 }
 
+// Outside of mpv, as in danmaku2ass, the config directory is found the way
+// mpv finds it.
+fn expand_path_headless(path: &str) -> Result<String> {
+    use std::env::var;
+
+    let home = || var("HOME").or_else(|_| var("USERPROFILE"));
+    if let Some(rest) = path.strip_prefix("~~/") {
+        let config = match var("MPV_HOME") {
+            Ok(dir) => dir,
+            Err(_) if cfg!(target_os = "windows") => format!("{}/mpv", var("APPDATA")?),
+            Err(_) => match var("XDG_CONFIG_HOME") {
+                Ok(dir) => format!("{}/mpv", dir),
+                Err(_) => format!("{}/.config/mpv", home()?),
+            },
+        };
+        Ok(format!("{}/{}", config, rest))
+    } else if let Some(rest) = path.strip_prefix("~/") {
+        Ok(format!("{}/{}", home()?, rest))
+    } else {
+        Ok(path.to_string())
+    }
+}
+
+pub fn command(args: &[&str]) {
+    let args = args
+        .iter()
+        .map(|arg| CString::new(*arg).unwrap())
+        .collect::<Vec<_>>();
+    let mut args = args
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .chain([null()])
+        .collect::<Vec<_>>();
+    let error = unsafe { mpv_command(CTX, args.as_mut_ptr()) };
+    if error < 0 {
+        log_code(error);
+    }
+}
+
 pub fn osd_message(text: &str) {
     let arg2 = CString::new(text).unwrap();
     let mut args = [c"show-text".as_ptr(), arg2.as_ptr(), null()];
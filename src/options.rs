@@ -1,11 +1,21 @@
-use crate::{CLIENT_NAME, dandanplay::Source, log::log_error, mpv::expand_path};
+use crate::{
+    CLIENT_NAME, blocklist,
+    dandanplay::{Danmaku, Source},
+    density,
+    log::log_error,
+    merge,
+    metrics::{self, Calibration},
+    mpv::{command, expand_path},
+};
 use anyhow::{Result, anyhow};
+use regex::Regex;
 use serde::Deserialize;
 use std::{
     collections::HashSet,
     fs::File,
     io::{BufRead, BufReader, ErrorKind},
-    sync::{Arc, LazyLock},
+    sync::{Arc, LazyLock, RwLock},
+    time::Duration,
 };
 use tokio::sync::Mutex;
 
@@ -16,16 +26,45 @@ struct BilibiliFilterRule {
     opened: bool,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Output {
+    Overlay,
+    Track,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LocalDanmaku {
+    No,
+    Replace,
+    Merge,
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Options {
     pub font_size: f64,
     pub transparency: u8,
     pub reserved_space: f64,
     pub speed: f64,
     pub no_overlap: bool,
-    pub proxy: &'static str,
-    pub user_agent: &'static str,
-    pub log: &'static str,
+    pub output: Output,
+    pub local_danmaku: LocalDanmaku,
+    pub local_danmaku_path: Arc<str>,
+    pub cache_ttl: f64,
+    pub cache_ttl_stable: f64,
+    pub api_base: Arc<str>,
+    pub app_id: Arc<str>,
+    pub app_secret: Arc<str>,
+    pub proxy: Arc<str>,
+    pub user_agent: Arc<str>,
+    pub log: Arc<str>,
+    pub reload_interval: f64,
+    pub click_menu: bool,
+    pub merge_window: f64,
+    pub merge_collapse_repeats: bool,
+    pub merge_fold_width: bool,
+    pub density: usize,
+    pub density_prefer: Arc<[Source]>,
+    pub width_calibration: Calibration,
 }
 
 impl Default for Options {
@@ -36,9 +75,25 @@ impl Default for Options {
             reserved_space: 0.,
             speed: 1.,
             no_overlap: true,
-            proxy: "",
-            user_agent: "libmpv",
-            log: "false",
+            output: Output::Overlay,
+            local_danmaku: LocalDanmaku::Replace,
+            local_danmaku_path: "{name}.xml".into(),
+            cache_ttl: 1.,
+            cache_ttl_stable: 30.,
+            api_base: "https://api.dandanplay.net".into(),
+            app_id: "".into(),
+            app_secret: "".into(),
+            proxy: "".into(),
+            user_agent: "libmpv".into(),
+            log: "false".into(),
+            reload_interval: 0.,
+            click_menu: false,
+            merge_window: 0.,
+            merge_collapse_repeats: true,
+            merge_fold_width: true,
+            density: 0,
+            density_prefer: Arc::new([]),
+            width_calibration: Calibration::default(),
         }
     }
 }
@@ -48,14 +103,106 @@ pub struct Filter {
     pub keywords: Vec<String>,
     pub sources: HashSet<Source>,
     pub sources_rt: Mutex<Option<HashSet<Source>>>,
+    pub keywords_rt: Mutex<Option<Vec<String>>>,
+    pub patterns: Vec<Regex>,
+    pub users: HashSet<String>,
 }
 
-pub fn read_options() -> Result<Option<(Options, Arc<Filter>)>> {
-    let path = expand_path(&format!(
+impl Filter {
+    // Sets `blocked` of every comment, merges the duplicates among what is
+    // left and measures them, then the density limit thins them out.
+    // `danmaku` must be sorted by time.
+    pub async fn apply(&self, danmaku: &mut [Danmaku]) {
+        self.block(danmaku).await;
+        let options = get();
+        merge::merge_duplicates(danmaku, &options);
+        for comment in danmaku.iter_mut().filter(|comment| !comment.blocked) {
+            comment.width = metrics::width(&comment.text(), &options.width_calibration);
+        }
+        density::limit(danmaku);
+    }
+
+    // Sets `blocked` of every comment, the runtime overrides from script-opts
+    // take the place of the configured keywords and sources. Users and
+    // keywords in the blocklists are always blocked.
+    pub async fn block(&self, danmaku: &mut [Danmaku]) {
+        let sources_rt = self.sources_rt.lock().await;
+        let keywords_rt = self.keywords_rt.lock().await;
+        let sources = sources_rt.as_ref().unwrap_or(&self.sources);
+        let keywords = keywords_rt.as_ref().unwrap_or(&self.keywords);
+        let blocked_users = blocklist::USERS.read();
+        let blocked_keywords = blocklist::KEYWORDS.read();
+        for comment in danmaku.iter_mut() {
+            comment.blocked = sources.contains(&comment.source)
+                || keywords.iter().any(|pat| comment.message.contains(pat))
+                || self.patterns.iter().any(|re| re.is_match(&comment.message))
+                || self.users.contains(&comment.user)
+                || blocked_users.contains(&comment.user)
+                || blocked_keywords
+                    .iter()
+                    .any(|pat| comment.message.contains(pat.as_str()));
+        }
+    }
+}
+
+// Options that may also be changed at runtime through script-opts.
+pub fn set_render_option(opts: &mut Options, k: &str, v: &str) -> bool {
+    match k {
+        "font_size" => {
+            if let Some(f) = v.parse().ok().filter(|&f| f > 0.) {
+                opts.font_size = f;
+            }
+        }
+        "transparency" => {
+            if let Ok(t) = v.parse() {
+                opts.transparency = t;
+            }
+        }
+        "reserved_space" => {
+            if let Some(r) = v.parse().ok().filter(|r| (0. ..1.).contains(r)) {
+                opts.reserved_space = r;
+            }
+        }
+        "speed" => {
+            if let Some(s) = v.parse().ok().filter(|s| *s > 0.) {
+                opts.speed = s;
+            }
+        }
+        "no_overlap" => match v {
+            "yes" => opts.no_overlap = true,
+            "no" => opts.no_overlap = false,
+            _ => (),
+        },
+        "output" => match v {
+            "overlay" => opts.output = Output::Overlay,
+            "track" => opts.output = Output::Track,
+            _ => (),
+        },
+        _ => return false,
+    }
+    true
+}
+
+pub fn parse_keywords(v: &str) -> Vec<String> {
+    v.split(',').map(Into::into).collect()
+}
+
+pub fn parse_sources(v: &str) -> HashSet<Source> {
+    v.split(',')
+        .map(Source::from)
+        .filter(|&s| s != Source::Unknown)
+        .collect()
+}
+
+fn conf_path() -> Result<String> {
+    expand_path(&format!(
         "~~/script-opts/{}.conf",
         CLIENT_NAME.get().unwrap_or(&"".to_string())
-    ))?;
-    let file = match File::open(path) {
+    ))
+}
+
+pub fn read_options() -> Result<Option<(Options, Arc<Filter>)>> {
+    let file = match File::open(conf_path()?) {
         Ok(file) => file,
         Err(error) => {
             return if error.kind() == ErrorKind::NotFound {
@@ -74,58 +221,117 @@ pub fn read_options() -> Result<Option<(Options, Arc<Filter>)>> {
             continue;
         }
         if let Some((k, v)) = line.split_once('=') {
+            if set_render_option(&mut opts, k, v) {
+                continue;
+            }
             match k {
-                "font_size" => {
-                    if let Some(f) = v.parse().ok().filter(|&f| f > 0.) {
-                        opts.font_size = f;
-                    }
+                "local_danmaku" => match v {
+                    "no" => opts.local_danmaku = LocalDanmaku::No,
+                    "replace" => opts.local_danmaku = LocalDanmaku::Replace,
+                    "merge" => opts.local_danmaku = LocalDanmaku::Merge,
+                    _ => (),
+                },
+                "local_danmaku_path" if !v.is_empty() => {
+                    opts.local_danmaku_path = v.into();
                 }
-                "transparency" => {
-                    if let Ok(t) = v.parse() {
-                        opts.transparency = t;
+                "cache_ttl" => {
+                    if let Some(t) = v.parse().ok().filter(|&t| t >= 0.) {
+                        opts.cache_ttl = t;
                     }
                 }
-                "reserved_space" => {
-                    if let Some(r) = v.parse().ok().filter(|r| (0. ..1.).contains(r)) {
-                        opts.reserved_space = r;
+                "cache_ttl_stable" => {
+                    if let Some(t) = v.parse().ok().filter(|&t| t >= 0.) {
+                        opts.cache_ttl_stable = t;
                     }
                 }
-                "speed" => {
-                    if let Some(s) = v.parse().ok().filter(|s| *s > 0.) {
-                        opts.speed = s;
-                    }
+                "api_base" if v.starts_with("http") => {
+                    opts.api_base = v.trim_end_matches('/').into();
+                }
+                "app_id" if !v.is_empty() => {
+                    opts.app_id = v.into();
+                }
+                "app_secret" if !v.is_empty() => {
+                    opts.app_secret = v.into();
                 }
-                "no_overlap" => match v {
-                    "yes" => opts.no_overlap = true,
-                    "no" => opts.no_overlap = false,
-                    _ => (),
-                },
                 "proxy" if !v.is_empty() && v.starts_with("http") => {
-                    opts.proxy = Box::leak(v.to_string().into_boxed_str());
+                    opts.proxy = v.into();
                 }
                 "user_agent" if !v.is_empty() => {
-                    opts.user_agent = Box::leak(v.to_string().into_boxed_str());
+                    opts.user_agent = v.into();
                 }
                 "log" if !v.is_empty() => {
-                    opts.log = Box::leak(v.to_string().into_boxed_str());
+                    opts.log = v.into();
+                }
+                "click_menu" => match v {
+                    "yes" => opts.click_menu = true,
+                    "no" => opts.click_menu = false,
+                    _ => (),
+                },
+                "merge_window" => {
+                    if let Some(w) = v.parse().ok().filter(|&w| w >= 0.) {
+                        opts.merge_window = w;
+                    }
+                }
+                "merge_normalize" => {
+                    let methods = v.split(',').collect::<Vec<_>>();
+                    opts.merge_collapse_repeats = methods.contains(&"repeat");
+                    opts.merge_fold_width = methods.contains(&"width");
+                }
+                "density" => {
+                    if let Ok(d) = v.parse() {
+                        opts.density = d;
+                    }
                 }
-                "filter" if !v.is_empty() => filter.keywords.extend(v.split(',').map(Into::into)),
-                "filter_source" if !v.is_empty() => filter.sources.extend(
-                    v.split(',')
+                "density_prefer" => {
+                    opts.density_prefer = v
+                        .split(',')
                         .map(Source::from)
-                        .filter(|&s| s != Source::Unknown),
-                ),
+                        .filter(|&s| s != Source::Unknown)
+                        .collect();
+                }
+                "width_calibration" => match Calibration::parse(v) {
+                    Some(calibration) => opts.width_calibration = calibration,
+                    None => log_error(&anyhow!("option width_calibration: invalid value {}", v)),
+                },
+                "reload_interval" => {
+                    if let Some(i) = v.parse().ok().filter(|&i| i >= 0.) {
+                        opts.reload_interval = i;
+                    }
+                }
+                "filter" if !v.is_empty() => filter.keywords.extend(parse_keywords(v)),
+                "filter_source" if !v.is_empty() => filter.sources.extend(parse_sources(v)),
                 "filter_bilibili" if !v.is_empty() => match (|| -> Result<_> {
                     Ok(serde_json::from_reader::<_, Vec<BilibiliFilterRule>>(
                         BufReader::new(File::open(expand_path(v)?)?),
                     )?)
                 })() {
-                    Ok(rules) => filter.keywords.extend(
-                        rules
-                            .into_iter()
-                            .filter(|r| r.r#type == 0 && r.opened)
-                            .map(|r| r.filter),
-                    ),
+                    Ok(rules) => {
+                        for rule in rules.into_iter().filter(|r| r.opened) {
+                            match rule.r#type {
+                                0 => filter.keywords.push(rule.filter),
+                                1 => {
+                                    // exported regexes may keep the slashes around them
+                                    let pattern = rule
+                                        .filter
+                                        .strip_prefix('/')
+                                        .and_then(|p| p.strip_suffix('/'))
+                                        .unwrap_or(&rule.filter);
+                                    match Regex::new(pattern) {
+                                        Ok(regex) => filter.patterns.push(regex),
+                                        Err(error) => log_error(&anyhow!(
+                                            "option filter_bilibili: invalid regex {}: {}",
+                                            rule.filter,
+                                            error
+                                        )),
+                                    }
+                                }
+                                2 => {
+                                    filter.users.insert(rule.filter);
+                                }
+                                _ => (),
+                            }
+                        }
+                    }
                     Err(error) => log_error(&anyhow!("option filter_bilibili: {}", error)),
                 },
                 _ => (),
@@ -135,11 +341,123 @@ pub fn read_options() -> Result<Option<(Options, Arc<Filter>)>> {
     Ok(Some((opts, Arc::new(filter))))
 }
 
-pub static OPTIONS: LazyLock<Options> = LazyLock::new(|| {
-    read_options()
-        .map_err(|e| crate::log::log_error(&e))
-        .ok()
-        .flatten()
-        .unwrap_or_default()
-        .0
-});
+// options from the conf file, replaced when it is reloaded
+static OPTIONS: LazyLock<RwLock<Options>> = LazyLock::new(Default::default);
+
+pub fn get() -> Options {
+    OPTIONS.read().unwrap().clone()
+}
+
+pub fn set(options: Options) {
+    *OPTIONS.write().unwrap() = options;
+}
+
+// Describes what differs between two versions of the conf file.
+pub fn changes(old: (&Options, &Filter), new: (&Options, &Filter)) -> Vec<String> {
+    let mut changes = Vec::new();
+    macro_rules! compare {
+        ($($field:ident),*) => {
+            $(
+                if old.0.$field != new.0.$field {
+                    changes.push(format!(
+                        "{}: {:?} -> {:?}",
+                        stringify!($field),
+                        old.0.$field,
+                        new.0.$field
+                    ));
+                }
+            )*
+        };
+    }
+    compare!(
+        font_size,
+        transparency,
+        reserved_space,
+        speed,
+        no_overlap,
+        output,
+        local_danmaku,
+        local_danmaku_path,
+        cache_ttl,
+        cache_ttl_stable,
+        api_base,
+        app_id,
+        reload_interval,
+        click_menu,
+        density,
+        density_prefer
+    );
+    if old.0.app_secret != new.0.app_secret {
+        changes.push("app_secret".to_string());
+    }
+    // the http client and the logger are set up once
+    for (name, changed) in [
+        ("proxy", old.0.proxy != new.0.proxy),
+        ("user_agent", old.0.user_agent != new.0.user_agent),
+        ("log", old.0.log != new.0.log),
+    ] {
+        if changed {
+            changes.push(format!("{} (after restart)", name));
+        }
+    }
+    for (name, changed) in [
+        ("merge_window", old.0.merge_window != new.0.merge_window),
+        (
+            "merge_normalize",
+            old.0.merge_collapse_repeats != new.0.merge_collapse_repeats
+                || old.0.merge_fold_width != new.0.merge_fold_width,
+        ),
+        (
+            "width_calibration",
+            old.0.width_calibration != new.0.width_calibration,
+        ),
+    ] {
+        if changed {
+            changes.push(name.to_string());
+        }
+    }
+    if old.1.keywords != new.1.keywords {
+        changes.push(format!("filter: {} keywords", new.1.keywords.len()));
+    }
+    if old.1.patterns.len() != new.1.patterns.len()
+        || old.1.users != new.1.users
+        || old
+            .1
+            .patterns
+            .iter()
+            .zip(&new.1.patterns)
+            .any(|(a, b)| a.as_str() != b.as_str())
+    {
+        changes.push(format!(
+            "filter_bilibili: {} regexes, {} users",
+            new.1.patterns.len(),
+            new.1.users.len()
+        ));
+    }
+    if old.1.sources != new.1.sources {
+        changes.push(format!("filter_source: {:?}", new.1.sources));
+    }
+    changes
+}
+
+// Asks the script to reload the conf file whenever it is modified.
+pub async fn watch(interval: f64) {
+    if interval <= 0. {
+        return;
+    }
+    let modified = || {
+        std::fs::metadata(conf_path().ok()?)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    let client = CLIENT_NAME.get().map_or("", |name| name.as_str());
+    let mut last = modified();
+    loop {
+        tokio::time::sleep(Duration::from_secs_f64(interval)).await;
+        let current = modified();
+        if current != last {
+            last = current;
+            command(&["script-message-to", client, "danmaku-reload-config"]);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{
+    comments::{Comments, Shown},
+    dandanplay::{Danmaku, Mode, Status, StatusInner},
+    options::Options,
+};
+use rand::{Rng, rng};
+use std::fmt::Write;
+
+const MAX_DURATION: f64 = 12.;
+pub const INTERVAL: f64 = 0.005;
+const MIN_STEP: f64 = INTERVAL / MAX_DURATION;
+const MAX_STEP: f64 = MIN_STEP * 1.3;
+const FIXED_DURATION: f64 = 5.;
+// how long before the current position a comment may have started and still be visible
+pub const WINDOW: f64 = MAX_DURATION * 2.;
+
+#[derive(Default, Clone, Copy)]
+pub struct Params {
+    pub delay: f64,
+    pub speed: f64,
+    pub pause: bool,
+    pub osd_width: f64,
+    pub osd_height: f64,
+    // whether the output is rendered at the playback position, as subtitles
+    // are. Comments are then placed by the position and animated by libass
+    // with `\move` and `\t`. osd-overlay is always rendered at time 0, so
+    // there they are moved by a step on every redraw.
+    pub timed: bool,
+}
+
+// an ASS event drawn at some playback position
+pub struct Event {
+    // position in `danmaku`
+    pub index: usize,
+    pub text: String,
+    // playback position at which the comment has left the screen
+    pub until: f64,
+}
+
+pub struct Frame {
+    pub events: Vec<Event>,
+    pub width: f64,
+    pub height: f64,
+    // when the output has to be refreshed next, only when animated
+    pub next: Option<f64>,
+}
+
+#[derive(Clone, Copy)]
+struct Row {
+    end: f64,
+    step: f64,
+}
+
+// Lays out the comments visible at playback position `pos`.
+pub fn layout(comments: &mut Comments, pos: f64, params: Params, options: &Options) -> Frame {
+    let animate = params.timed && !params.pause;
+    // wake up slightly early rather than spinning until the next comment is due
+    let horizon = if animate { pos + INTERVAL } else { pos };
+    let mut width = 1920.;
+    let mut height = 1080.;
+    let ratio = params.osd_width / params.osd_height;
+    if width / height < ratio {
+        height = width / ratio;
+    } else if width / height > ratio {
+        width = height * ratio;
+    }
+    let spacing = options.font_size / 10.;
+    let mut rows = vec![
+        Row {
+            end: 0.,
+            step: MIN_STEP,
+        };
+        ((height * (1. - options.reserved_space) / (options.font_size + spacing))
+            as usize)
+            .max(1)
+    ];
+
+    // end time of the fixed comment occupying each row, top and bottom
+    let mut top = vec![None; rows.len()];
+    let mut bottom = vec![None; rows.len()];
+    let bottom_edge = height * (1. - options.reserved_space);
+
+    let mut events = Vec::new();
+    comments.shown.clear();
+    comments.frame = (width, height);
+    let mut next = None;
+    let mut rng = rng();
+    let start = comments.cursor;
+    let mut settled = true;
+    'it: for (n, &i) in comments.index[start..].iter().enumerate() {
+        // nothing before this comment will be shown again until the next reset
+        if settled {
+            comments.cursor = start + n;
+        }
+        let comment = &mut comments.danmaku[i];
+        let time = comment.time + params.delay;
+        let text_width = comment.width * options.font_size * comment.size();
+        let text_height = options.font_size * comment.size();
+        if time > horizon {
+            next = Some(time);
+            break;
+        }
+
+        if comment.mode != Mode::Scroll {
+            if pos - time > FIXED_DURATION {
+                continue;
+            }
+            let fixed = if comment.mode == Mode::Top {
+                &mut top
+            } else {
+                &mut bottom
+            };
+            let status = match &mut comment.status {
+                Status::Status(status) => status,
+                Status::Overlapping => continue,
+                Status::Uninitialized => {
+                    let row = match fixed.iter().position(Option::is_none) {
+                        Some(row) => row,
+                        None if options.no_overlap => {
+                            comment.status = Status::Overlapping;
+                            continue;
+                        }
+                        None => fixed
+                            .iter()
+                            .enumerate()
+                            .min_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+                            .map(|(row, _)| row)
+                            .unwrap(),
+                    };
+                    comment.status.insert(StatusInner {
+                        x: width / 2.,
+                        row,
+                        step: 0.,
+                    })
+                }
+            };
+            let &mut StatusInner { x, row, .. } = status;
+            if let Some(end) = fixed.get_mut(row) {
+                *end = Some(time + FIXED_DURATION);
+            }
+            let (alignment, y) = if comment.mode == Mode::Top {
+                (8, row as f64 * (options.font_size + spacing))
+            } else {
+                (2, bottom_edge - row as f64 * (options.font_size + spacing))
+            };
+            let hide = if animate {
+                let t = (time + FIXED_DURATION - pos) / params.speed * 1000.;
+                format!("\\t({t:.0},{t:.0},\\alpha&HFF&)")
+            } else {
+                String::new()
+            };
+            comments.shown.push(Shown {
+                index: i,
+                x: x - text_width / 2.,
+                y: if alignment == 8 { y } else { y - text_height },
+                width: text_width,
+                height: text_height,
+                velocity: 0.,
+                pos,
+                until: time + FIXED_DURATION,
+            });
+            settled = false;
+            events.push(Event {
+                index: i,
+                text: format!(
+                    "{{\\an{}\\pos({},{}){}{}}}{}",
+                    alignment,
+                    x,
+                    y,
+                    style(comment, options),
+                    hide,
+                    comment.text()
+                ),
+                until: time + FIXED_DURATION,
+            });
+            continue;
+        }
+
+        let status = match &mut comment.status {
+            Status::Status(status) => status,
+            Status::Overlapping => continue,
+            Status::Uninitialized => 'status: {
+                let ticks = (pos - time) / INTERVAL;
+                for (row, status) in rows.iter().enumerate() {
+                    if status.end < width - width * ticks * MIN_STEP {
+                        let max_step = if status.end == 0. {
+                            MAX_STEP
+                        } else {
+                            // 1 / max_step - ticks = status.end / width / status.step
+                            let max_step = 1. / (ticks + status.end / width / status.step);
+                            max_step.min(MAX_STEP)
+                        };
+                        let step = rng.random_range(MIN_STEP..max_step);
+                        let x = width - width * ticks * step;
+                        break 'status comment.status.insert(StatusInner { x, row, step });
+                    }
+                }
+                if options.no_overlap {
+                    comment.status = Status::Overlapping;
+                    continue 'it;
+                }
+                let row = rows
+                    .iter()
+                    .enumerate()
+                    .min_by(|a, b| a.1.end.partial_cmp(&b.1.end).unwrap())
+                    .map(|(row, _)| row)
+                    .unwrap();
+                let step = MIN_STEP;
+                let x = width - width * ticks * step;
+                comment.status.insert(StatusInner { x, row, step })
+            }
+        };
+        // pixels per second of video time
+        let velocity = width * status.step * options.speed / INTERVAL;
+        if params.timed {
+            status.x = width - velocity * (pos - time);
+        }
+        let length = text_width + spacing;
+        if status.x + length <= 0. {
+            continue;
+        }
+        let &mut StatusInner { x, row, .. } = status;
+
+        if !params.timed {
+            status.x -= width * status.step * params.speed * options.speed;
+        }
+        if let Some(row) = rows.get_mut(status.row) {
+            let end = status.x + length;
+            if end / status.step > row.end / row.step {
+                *row = Row {
+                    end,
+                    step: status.step,
+                };
+            }
+        }
+
+        let y = row as f64 * (options.font_size + spacing);
+        let position = if animate {
+            let t = (x + length) / velocity / params.speed * 1000.;
+            format!("\\move({},{},{},{},0,{:.0})", x, y, -length, y, t)
+        } else {
+            format!("\\pos({},{})", x, y)
+        };
+        comments.shown.push(Shown {
+            index: i,
+            x,
+            y,
+            width: text_width,
+            height: text_height,
+            velocity,
+            pos,
+            until: f64::INFINITY,
+        });
+        settled = false;
+        events.push(Event {
+            index: i,
+            text: format!(
+                "{{{}{}}}{}",
+                position,
+                style(comment, options),
+                comment.text()
+            ),
+            until: pos + (x + length) / velocity,
+        });
+    }
+    if settled && next.is_none() {
+        comments.cursor = comments.index.len();
+    }
+
+    Frame {
+        events,
+        width,
+        height,
+        next: next.filter(|_| animate),
+    }
+}
+
+fn style(comment: &Danmaku, options: &Options) -> String {
+    format!(
+        "\\c&H{:02x}{:02x}{:02x}&\\alpha&H{:02x}\\fs{}\\bord1.5\\shad0\\b1\\q2",
+        comment.b,
+        comment.g,
+        comment.r,
+        options.transparency,
+        options.font_size * comment.size(),
+    )
+}
+
+// Lays out all comments as they are shown when the video is played from the
+// start without pausing, as a standalone ASS script. Only the ratio of
+// `osd_width` to `osd_height` matters.
+pub fn to_ass(comments: &mut Comments, params: Params, options: &Options) -> String {
+    // subtitles are timed in video time
+    let params = Params {
+        pause: false,
+        speed: 1.,
+        timed: true,
+        ..params
+    };
+    comments.reset(f64::NEG_INFINITY);
+
+    let mut written = vec![false; comments.danmaku.len()];
+    let mut dialogue = String::new();
+    let mut size = (1920., 1080.);
+    let mut pos = comments
+        .index
+        .first()
+        .map(|&i| comments.danmaku[i].time + params.delay);
+    while let Some(at) = pos {
+        let frame = layout(comments, at, params, options);
+        size = (frame.width, frame.height);
+        // every comment keeps moving the way it was laid out when it appeared
+        for event in frame.events {
+            if !std::mem::replace(&mut written[event.index], true) {
+                _ = writeln!(
+                    dialogue,
+                    "Dialogue: 0,{},{},Default,,0,0,0,,{}",
+                    timestamp(at),
+                    timestamp(event.until),
+                    event.text.replace('\n', " ")
+                );
+            }
+        }
+        pos = frame.next;
+    }
+    // none of it is on screen
+    comments.shown.clear();
+
+    format!(
+        "[Script Info]\n\
+         ScriptType: v4.00+\n\
+         PlayResX: {}\n\
+         PlayResY: {}\n\
+         WrapStyle: 2\n\
+         ScaledBorderAndShadow: yes\n\
+         \n\
+         [V4+ Styles]\n\
+         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: Default,sans-serif,{},&H00FFFFFF,&H00FFFFFF,&H00000000,&H00000000,-1,0,0,0,100,100,0,0,1,1.5,0,7,0,0,0,1\n\
+         \n\
+         [Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+         {}",
+        size.0 as i64, size.1 as i64, options.font_size, dialogue
+    )
+}
+
+// h:mm:ss.cc
+fn timestamp(seconds: f64) -> String {
+    let centis = (seconds.max(0.) * 100.).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        centis / 360000,
+        centis / 6000 % 60,
+        centis / 100 % 60,
+        centis % 100
+    )
+}
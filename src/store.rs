@@ -0,0 +1,209 @@
+use crate::mpv::expand_path;
+use anyhow::{Result, anyhow};
+use md5::{Digest, Md5};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{io::ErrorKind, path::PathBuf};
+use tracing::info;
+
+// Files in ~~/files/danmaku are laid out as
+// magic (8 bytes) | version (u16 LE) | md5 of the payload (16 bytes) | payload
+// where the payload is the value encoded with bincode.
+const MAGIC: &[u8; 8] = b"DANMAKU\0";
+const HEADER: usize = MAGIC.len() + 2 + 16;
+
+// bytes a decoded value may take up
+const LIMIT: usize = 1 << 30;
+
+// the version of files written before the container existed
+pub const UNVERSIONED: u16 = 0;
+
+// A value kept in a file of its own, each type has its own versions.
+pub trait Stored: Serialize + DeserializeOwned {
+    // bumped whenever the layout of the type changes
+    const VERSION: u16;
+
+    // Decodes a payload written with an older version, or a file written
+    // before the container existed as `UNVERSIONED`.
+    fn migrate(version: u16, payload: &[u8]) -> Result<Self>;
+}
+
+pub fn encode<T: Stored>(value: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serde::encode_to_vec(value, bincode::config::legacy())?;
+    Ok(container(T::VERSION, &payload))
+}
+
+pub(crate) fn container(version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(HEADER + payload.len());
+    contents.extend_from_slice(MAGIC);
+    contents.extend_from_slice(&version.to_le_bytes());
+    contents.extend_from_slice(&Md5::digest(payload));
+    contents.extend_from_slice(payload);
+    contents
+}
+
+pub fn decode<T: Stored>(contents: &[u8]) -> Result<T> {
+    let Some(rest) = contents.strip_prefix(MAGIC) else {
+        return T::migrate(UNVERSIONED, contents);
+    };
+    if rest.len() < HEADER - MAGIC.len() {
+        return Err(anyhow!("truncated header"));
+    }
+    let (version, rest) = rest.split_at(2);
+    let (checksum, payload) = rest.split_at(16);
+
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    if version > T::VERSION || version == UNVERSIONED {
+        return Err(anyhow!("unsupported format version {}", version));
+    }
+    if Md5::digest(payload).as_slice() != checksum {
+        return Err(anyhow!("checksum mismatch"));
+    }
+
+    if version == T::VERSION {
+        decode_legacy(payload)
+    } else {
+        T::migrate(version, payload)
+    }
+}
+
+// Decodes a bincode blob without the container. All of it has to be used,
+// so that a blob of another layout is not mistaken for a shorter value, and
+// what it claims to hold is limited, so that one does not exhaust memory.
+pub fn decode_legacy<T: DeserializeOwned>(contents: &[u8]) -> Result<T> {
+    let config = bincode::config::legacy().with_limit::<LIMIT>();
+    let (value, read) = bincode::serde::decode_from_slice(contents, config)?;
+    if read != contents.len() {
+        return Err(anyhow!("{} bytes left over", contents.len() - read));
+    }
+    Ok(value)
+}
+
+// Writes to a temporary file first so that an interrupted write never leaves
+// a truncated file behind.
+pub async fn save<T: Stored>(path: &str, value: &T) -> Result<()> {
+    let path = PathBuf::from(expand_path(path)?);
+    let contents = encode(value)?;
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(tmp, path).await?;
+
+    Ok(())
+}
+
+// Returns the file contents, or None when there is no such file.
+pub async fn read(path: &str) -> Result<Option<Vec<u8>>> {
+    match tokio::fs::read(expand_path(path)?).await {
+        Ok(contents) => Ok(Some(contents)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+// Moves an unreadable file out of the way instead of overwriting it.
+pub async fn backup(path: &str) -> Result<String> {
+    let path = PathBuf::from(expand_path(path)?);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    let backup = path.with_file_name(name);
+    tokio::fs::rename(&path, &backup).await?;
+    info!("Backed up {} to {}", path.display(), backup.display());
+
+    Ok(backup.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Old {
+        a: u32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct New {
+        a: u32,
+        b: String,
+    }
+
+    impl Stored for New {
+        const VERSION: u16 = 2;
+
+        // tells which version it was migrated from
+        fn migrate(version: u16, payload: &[u8]) -> Result<Self> {
+            let old = decode_legacy::<Old>(payload)?;
+            Ok(New {
+                a: old.a,
+                b: format!("from {}", version),
+            })
+        }
+    }
+
+    fn payload<T: Serialize>(value: &T) -> Vec<u8> {
+        bincode::serde::encode_to_vec(value, bincode::config::legacy()).unwrap()
+    }
+
+    fn new() -> New {
+        New {
+            a: 7,
+            b: "database".to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let contents = encode(&new()).unwrap();
+        assert!(contents.starts_with(MAGIC));
+        assert_eq!(decode::<New>(&contents).unwrap(), new());
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let contents = encode(&new()).unwrap();
+
+        let mut flipped = contents.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        let mut checksum = contents.clone();
+        checksum[HEADER - 1] ^= 1;
+        let mut longer = contents[HEADER..].to_vec();
+        longer.push(0);
+        let cases = [
+            (flipped, "checksum mismatch"),
+            (checksum, "checksum mismatch"),
+            (contents[..HEADER - 1].to_vec(), "truncated header"),
+            (
+                container(New::VERSION + 1, &contents[HEADER..]),
+                "unsupported format version 3",
+            ),
+            (
+                container(UNVERSIONED, &contents[HEADER..]),
+                "unsupported format version 0",
+            ),
+            (container(New::VERSION, &longer), "1 bytes left over"),
+        ];
+        for (contents, error) in cases {
+            assert_eq!(decode::<New>(&contents).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn migrates_by_version() {
+        let old = payload(&Old { a: 7 });
+        let migrated = |version: u16| New {
+            a: 7,
+            b: format!("from {}", version),
+        };
+
+        // written before the container existed
+        assert_eq!(decode::<New>(&old).unwrap(), migrated(UNVERSIONED));
+        // written with an older layout
+        assert_eq!(decode::<New>(&container(1, &old)).unwrap(), migrated(1));
+        // the current version is decoded as it is
+        assert!(decode::<New>(&container(New::VERSION, &old)).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{
+    CLIENT_NAME,
+    comments::Comments,
+    log::log_error,
+    mpv::{command, get_property_f64, get_property_string},
+    options::Options,
+    render::{Params, to_ass},
+};
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+// The comments as a secondary subtitle track, which unlike the OSD overlay
+// ends up in screenshots with subtitles and in encodes.
+#[derive(Default)]
+pub struct Track {
+    id: Option<i64>,
+    // revision of the comments, delay, aspect ratio and options the track was
+    // generated from
+    source: Option<(u64, f64, f64, Options)>,
+    // secondary-sub-ass-override and secondary-sid before the track was
+    // selected, restored when it is removed
+    previous: Option<(String, String)>,
+}
+
+impl Track {
+    // Generates the track again when anything it depends on has changed.
+    // Returns whether it did.
+    pub fn update(&mut self, comments: &mut Comments, params: Params, options: &Options) -> bool {
+        let source = (
+            comments.revision,
+            params.delay,
+            params.osd_width / params.osd_height,
+            options.clone(),
+        );
+        if self.source.as_ref() == Some(&source) {
+            return false;
+        }
+        if let Some(id) = self.id.take() {
+            command(&["sub-remove", &id.to_string()]);
+        }
+        self.source = Some(source);
+
+        let path = path();
+        if let Err(error) = fs::write(&path, to_ass(comments, params, options)) {
+            log_error(&error.into());
+            return false;
+        }
+        command(&["sub-add", &path.to_string_lossy(), "auto", "Danmaku"]);
+        self.id = find_track(&path);
+        if let Some(id) = self.id {
+            if self.previous.is_none() {
+                self.previous = get_property_string(c"secondary-sub-ass-override")
+                    .zip(get_property_string(c"secondary-sid"));
+            }
+            // secondary subtitles are stripped of their styling by default
+            command(&["set", "secondary-sub-ass-override", "no"]);
+            command(&["set", "secondary-sid", &id.to_string()]);
+        }
+        true
+    }
+
+    pub fn remove(&mut self) {
+        if let Some(id) = self.id.take() {
+            command(&["sub-remove", &id.to_string()]);
+        }
+        self.forget();
+    }
+
+    // The track has gone away with the file it was added to.
+    pub fn forget(&mut self) {
+        self.id = None;
+        self.source = None;
+        if let Some((ass_override, sid)) = self.previous.take() {
+            command(&["set", "secondary-sub-ass-override", &ass_override]);
+            command(&["set", "secondary-sid", &sid]);
+        }
+    }
+}
+
+impl Drop for Track {
+    fn drop(&mut self) {
+        _ = fs::remove_file(path());
+    }
+}
+
+fn path() -> PathBuf {
+    env::temp_dir().join(format!(
+        "{}-{}.ass",
+        CLIENT_NAME.get().map_or("danmaku", |name| name.as_str()),
+        process::id()
+    ))
+}
+
+// the external subtitle track loaded from `path`
+fn find_track(path: &Path) -> Option<i64> {
+    let count = get_property_f64(c"track-list/count")? as i64;
+    let path = path.to_string_lossy();
+    (0..count).rev().find_map(|n| {
+        let property = |name: &str| CString::new(format!("track-list/{n}/{name}")).unwrap();
+        // only external tracks have a filename
+        if get_property_string(&property("external")).as_deref() != Some("yes")
+            || get_property_string(&property("external-filename")).as_deref() != Some(&*path)
+        {
+            return None;
+        }
+        get_property_f64(&property("id")).map(|id| id as i64)
+    })
+}
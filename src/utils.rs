@@ -1,4 +1,8 @@
-use crate::options;
+use crate::{
+    filename::{episode_number, parse},
+    log::log_error,
+    options, store,
+};
 use anyhow::{Result, anyhow};
 use hex::encode;
 use md5::{Digest, Md5};
@@ -15,17 +19,17 @@ use tracing::{error, info};
 pub(crate) static CLIENT: LazyLock<Client> = LazyLock::new(build);
 
 fn build() -> reqwest::Client {
-    let options = *options::OPTIONS;
+    let options = options::get();
 
     if options.proxy.is_empty() {
         Client::builder()
-            .user_agent(options.user_agent)
+            .user_agent(&*options.user_agent)
             .build()
             .expect("Failed to build client")
     } else {
         Client::builder()
-            .proxy(reqwest::Proxy::all(options.proxy).unwrap())
-            .user_agent(options.user_agent)
+            .proxy(reqwest::Proxy::all(&*options.proxy).unwrap())
+            .user_agent(&*options.user_agent)
             .build()
             .expect("Failed to build client")
     }
@@ -109,36 +113,6 @@ pub struct Anime {
     pub anime_title: String,
 }
 
-// 求dandan返回结果中的前n季集数之和
-//
-pub fn get_dan_sum(list: &[Anime], index: i64) -> Result<u64> {
-    if index > list.len() as i64 || index < 0 {
-        return Err(anyhow!("beyond bound of list"));
-    }
-
-    let mut sum = 0;
-    for item in list.iter().take(index as usize) {
-        sum += item.episode_count;
-    }
-
-    Ok(sum)
-}
-
-// 求emby前n季集数和
-//
-pub fn get_em_sum(list: &[(u64, u64)], index: i64) -> Result<u64> {
-    if index > list.len() as i64 || index < 0 {
-        return Err(anyhow!("beyond bound of list"));
-    }
-
-    let mut sum = 0;
-    for item in list.iter().take(index as usize) {
-        sum += item.1;
-    }
-
-    Ok(sum)
-}
-
 pub fn get_localfile_name(path: &str) -> String {
     std::path::Path::new(path)
         .file_name()
@@ -147,6 +121,25 @@ pub fn get_localfile_name(path: &str) -> String {
         .to_string()
 }
 
+// mpv keeps relative paths as they were given
+fn directory(path: &str) -> Option<String> {
+    std::fs::canonicalize(path)
+        .ok()?
+        .parent()?
+        .to_str()
+        .map(String::from)
+}
+
+// The directory of a local file followed by the title in its name, which
+// together stand for a season of local files.
+fn local_season(path: &str) -> Option<String> {
+    let title = parse(path).title.to_lowercase();
+    if title.is_empty() {
+        return None;
+    }
+    Some(format!("{}/{}", directory(path)?, title))
+}
+
 pub fn get_localfile_hash(path: &str) -> Result<String> {
     use std::fs::File;
     use std::io::Read;
@@ -181,10 +174,65 @@ pub struct AnimeOffset {
     pub offset: i64,
 }
 
+const DATABASE: &str = "~~/files/danmaku/database";
+
+// local files are grouped into seasons by directory under this host
+const LOCAL_HOST: &str = "file://";
+
+// number of local files remembered by their hash
+const FILES_CAPACITY: usize = 500;
+
+// a local file as it was when it was hashed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileStamp {
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Linkage {
     pub items: HashMap<String, LimitedHashMap<String, TimesId>>,
     pub seasons: HashMap<String, LimitedHashMap<String, AnimeOffset>>,
+    pub files: LimitedHashMap<String, TimesId>,
+    // path -> hash, so that unchanged files are not read again
+    pub paths: LimitedHashMap<String, FileStamp>,
+}
+
+// database layout of version 1, before the hashes of files were remembered
+#[derive(Deserialize)]
+struct FilesLinkage {
+    items: HashMap<String, LimitedHashMap<String, TimesId>>,
+    seasons: HashMap<String, LimitedHashMap<String, AnimeOffset>>,
+    files: LimitedHashMap<String, TimesId>,
+}
+
+// database layout before matches of files were remembered
+#[derive(Deserialize)]
+struct LegacyLinkage {
+    items: HashMap<String, LimitedHashMap<String, TimesId>>,
+    seasons: HashMap<String, LimitedHashMap<String, AnimeOffset>>,
+}
+
+impl From<FilesLinkage> for Linkage {
+    fn from(legacy: FilesLinkage) -> Self {
+        Linkage {
+            items: legacy.items,
+            seasons: legacy.seasons,
+            files: legacy.files,
+            ..Linkage::new()
+        }
+    }
+}
+
+impl From<LegacyLinkage> for Linkage {
+    fn from(legacy: LegacyLinkage) -> Self {
+        Linkage {
+            items: legacy.items,
+            seasons: legacy.seasons,
+            ..Linkage::new()
+        }
+    }
 }
 
 impl Default for Linkage {
@@ -198,6 +246,8 @@ impl Linkage {
         Linkage {
             items: HashMap::new(),
             seasons: HashMap::new(),
+            files: LimitedHashMap::new(FILES_CAPACITY),
+            paths: LimitedHashMap::new(FILES_CAPACITY),
         }
     }
 
@@ -227,6 +277,119 @@ impl Linkage {
         self.seasons.get(host_key)?.get(season_id).copied()
     }
 
+    pub fn insert_files(&mut self, hash: &str, epid: usize) {
+        let timestamped_value = TimesId {
+            epid,
+            last_updated: SystemTime::now(),
+        };
+        self.files.insert(hash.to_string(), timestamped_value);
+    }
+
+    pub fn get_files(&self, hash: &str) -> Option<usize> {
+        self.files.get(hash).map(|tv| tv.epid)
+    }
+
+    pub fn remove_files(&mut self, hash: &str) -> bool {
+        self.files.remove(hash).is_some()
+    }
+
+    pub fn remove_items(&mut self, host_key: &str, item_id: &str) -> bool {
+        self.items
+            .get_mut(host_key)
+            .and_then(|items| items.remove(item_id))
+            .is_some()
+    }
+
+    pub fn remove_seasons(&mut self, host_key: &str, season_id: &str) -> bool {
+        self.seasons
+            .get_mut(host_key)
+            .and_then(|seasons| seasons.remove(season_id))
+            .is_some()
+    }
+
+    // The remembered hash of a local file, unless the file has changed since.
+    pub fn stamped_hash(&self, path: &str) -> Option<String> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?;
+        self.paths
+            .get(path)
+            .filter(|stamp| stamp.size == metadata.len() && stamp.modified == modified)
+            .map(|stamp| stamp.hash.clone())
+    }
+
+    // The hash of a local file, read from the file only when its size or
+    // modification time changed since it was last hashed.
+    pub fn file_hash(&mut self, path: &str) -> Result<String> {
+        if let Some(hash) = self.stamped_hash(path) {
+            return Ok(hash);
+        }
+
+        let metadata = std::fs::metadata(path)?;
+        let (size, modified) = (metadata.len(), metadata.modified()?);
+        let hash = get_localfile_hash(path)?;
+        self.paths.insert(
+            path.to_string(),
+            FileStamp {
+                size,
+                modified,
+                hash: hash.clone(),
+            },
+        );
+        Ok(hash)
+    }
+
+    // Remembers how the episode numbers in the file names of the directory of
+    // `path` relate to episode ids, as seasons of the local files. Only files
+    // whose names have the same title are taken for the same anime.
+    pub fn insert_directories(&mut self, path: &str, epid: usize) {
+        let (Some(season), Some(number)) = (local_season(path), episode_number(path)) else {
+            return;
+        };
+        let anime_id = epid / 10000;
+        let offset = (epid - anime_id * 10000) as i64 - number as i64;
+        info!(
+            "Episode {} is anime {} with offset {}, for the files in {}",
+            number, anime_id, offset, season
+        );
+        self.insert_seasons(LOCAL_HOST, &season, AnimeOffset { anime_id, offset });
+    }
+
+    // The episode of a local file from the episode number in its name and
+    // what is known about its directory.
+    pub fn get_directories(&self, path: &str) -> Option<usize> {
+        let AnimeOffset { anime_id, offset } =
+            self.get_seasons(LOCAL_HOST, &local_season(path)?)?;
+        let episode = episode_number(path)? as i64 + offset;
+        // the episode number is the last four digits of the episode id
+        (1..10000)
+            .contains(&episode)
+            .then(|| anime_id * 10000 + episode as usize)
+    }
+
+    pub fn remove_directories(&mut self, path: &str) -> bool {
+        local_season(path).is_some_and(|season| self.remove_seasons(LOCAL_HOST, &season))
+    }
+
+    // Remembers the episode of a local file, by its hash when it can be
+    // hashed, and for the files of its title in its directory.
+    pub fn link_file(&mut self, path: &str, epid: usize) {
+        self.insert_directories(path, epid);
+        // files too small to hash are matched by name
+        if let Ok(hash) = self.file_hash(path) {
+            self.insert_files(&hash, epid);
+        }
+    }
+
+    // Returns false when nothing was remembered for the file.
+    pub fn forget_file(&mut self, path: &str) -> bool {
+        // the directory would match the file the same way again
+        let forgotten = self.remove_directories(path);
+        forgotten
+            | self
+                .file_hash(path)
+                .is_ok_and(|hash| self.remove_files(&hash))
+    }
+
     pub fn clean_expired_entries(&mut self, expiration_duration: Duration) {
         let now = SystemTime::now();
         self.items.retain(|_, inner_map| {
@@ -240,47 +403,52 @@ impl Linkage {
     }
 
     pub async fn save_as_bincode(&self) -> Result<()> {
-        use crate::mpv::expand_path;
-        use std::path::Path;
-        use tokio::io::AsyncWriteExt;
-
-        let encoded: Vec<u8> = bincode::serde::encode_to_vec(self, bincode::config::legacy())?;
-        let path_str = expand_path("~~/files/danmaku/database")?;
-        let path = Path::new(&path_str);
-
-        if !path.parent().expect("no parent dir").exists() {
-            std::fs::create_dir_all(path.parent().expect("no parent dir"))?;
-        }
-
-        let mut file = tokio::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(path)
-            .await?;
-
-        file.write_all(&encoded).await?;
-
-        Ok(())
+        store::save(DATABASE, self).await
     }
 
-    pub async fn load_from_bincode() -> Result<Self> {
-        use super::mpv::expand_path;
-        use std::path::Path;
-        use tokio::fs::File;
-        use tokio::io::AsyncReadExt;
+    // Ok(None) when there is no database yet.
+    pub async fn load_from_bincode() -> Result<Option<Self>> {
+        let Some(contents) = store::read(DATABASE).await? else {
+            return Ok(None);
+        };
 
-        let path_str = expand_path("~~/files/danmaku/database")?;
-        let path = Path::new(&path_str);
+        Self::decode(&contents).map(Some)
+    }
 
-        let mut file = File::open(path).await?;
-        let mut contents = vec![];
+    fn decode(contents: &[u8]) -> Result<Self> {
+        store::decode(contents)
+    }
 
-        file.read_to_end(&mut contents).await?;
+    // Starts over with an empty database when the current one can not be
+    // read, after moving it aside so that it is not overwritten.
+    pub async fn load() -> Self {
+        match Self::load_from_bincode().await {
+            Ok(linkage) => linkage.unwrap_or_default(),
+            Err(error) => {
+                log_error(&anyhow!("failed to read matching database: {}", error));
+                if let Err(error) = store::backup(DATABASE).await {
+                    log_error(&anyhow!("failed to back up matching database: {}", error));
+                }
+                Linkage::new()
+            }
+        }
+    }
+}
 
-        let linkage: Linkage =
-            bincode::serde::decode_from_slice(&contents, bincode::config::legacy())?.0;
-        Ok(linkage)
+// 1: the first container
+// 2: the hashes of local files are remembered by path
+impl store::Stored for Linkage {
+    const VERSION: u16 = 2;
+
+    fn migrate(version: u16, payload: &[u8]) -> Result<Self> {
+        match version {
+            // matches of files started being remembered before the container
+            store::UNVERSIONED => store::decode_legacy::<FilesLinkage>(payload)
+                .map(Linkage::from)
+                .or_else(|_| store::decode_legacy::<LegacyLinkage>(payload).map(Linkage::from)),
+            1 => store::decode_legacy::<FilesLinkage>(payload).map(Linkage::from),
+            _ => Err(anyhow!("unsupported database version {}", version)),
+        }
     }
 }
 
@@ -333,6 +501,17 @@ impl<K: std::hash::Hash + Eq + Clone, V> LimitedHashMap<K, V> {
         self.map.get(key)
     }
 
+    fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        Q: ?Sized,
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let value = self.map.remove(key)?;
+        self.keys.retain(|k| k.borrow() != key);
+        Some(value)
+    }
+
     fn _len(&self) -> usize {
         self.map.len()
     }
@@ -341,3 +520,124 @@ impl<K: std::hash::Hash + Eq + Clone, V> LimitedHashMap<K, V> {
         self.map.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload<T: Serialize>(value: &T) -> Vec<u8> {
+        bincode::serde::encode_to_vec(value, bincode::config::legacy()).unwrap()
+    }
+
+    fn linkage() -> Linkage {
+        let mut linkage = Linkage::new();
+        linkage.insert_items("http://emby", "101", 123450001);
+        linkage.insert_seasons(
+            "http://emby",
+            "100",
+            AnimeOffset {
+                anime_id: 12345,
+                offset: 0,
+            },
+        );
+        linkage.insert_files("0123456789abcdef", 678900002);
+        linkage
+    }
+
+    fn assert_kept(decoded: &Linkage, files: bool) {
+        assert_eq!(decoded.get_items("http://emby", "101"), Some(123450001));
+        assert_eq!(
+            decoded
+                .get_seasons("http://emby", "100")
+                .map(|season| season.anime_id),
+            Some(12345)
+        );
+        let file = decoded.get_files("0123456789abcdef");
+        assert_eq!(file, files.then_some(678900002));
+    }
+
+    #[test]
+    fn decodes_every_database_layout() {
+        let linkage = linkage();
+        let files = payload(&(&linkage.items, &linkage.seasons, &linkage.files));
+        let cases = [
+            // the current layout
+            (store::encode(&linkage).unwrap(), true),
+            // version 1, before the hashes of files were remembered by path
+            (store::container(1, &files), true),
+            // before the container, with and without matches of files
+            (files.clone(), true),
+            (payload(&(&linkage.items, &linkage.seasons)), false),
+        ];
+        for (contents, files) in cases {
+            assert_kept(&Linkage::decode(&contents).unwrap(), files);
+        }
+    }
+
+    #[test]
+    fn rejects_a_database_it_can_not_read() {
+        let mut contents = store::encode(&linkage()).unwrap();
+        *contents.last_mut().unwrap() ^= 1;
+        assert!(Linkage::decode(&contents).is_err());
+        assert!(Linkage::decode(b"DANMAKU\0").is_err());
+        assert!(Linkage::decode(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn infers_episodes_of_the_same_title() {
+        let directory = std::env::temp_dir().join(format!("danmaku-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let file = |name: &str| {
+            let path = directory.join(name);
+            std::fs::write(&path, b"").unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let mut linkage = Linkage::new();
+        linkage.insert_directories(&file("[Group] Frieren - 05 [1080p].mkv"), 123450005);
+        linkage.insert_directories(&file("[Group] Spy x Family - 01.mkv"), 678909500);
+
+        let cases = [
+            ("[Group] Frieren - 07 [1080p].mkv", Some(123450007)),
+            ("[Other] frieren - 08.mkv", Some(123450008)),
+            // another title in the same directory
+            ("[Group] Oshi no Ko - 07.mkv", None),
+            ("[07].mkv", None),
+            // the episode would not fit in the episode id
+            ("[Group] Spy x Family - 499.mkv", Some(678909998)),
+            ("[Group] Spy x Family - 501.mkv", None),
+        ];
+        for (name, episode_id) in cases {
+            assert_eq!(linkage.get_directories(&file(name)), episode_id, "{}", name);
+        }
+        assert!(linkage.remove_directories(&file("[Group] Frieren - 01.mkv")));
+        assert_eq!(
+            linkage.get_directories(&file("[Group] Frieren - 07.mkv")),
+            None
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn links_files_too_small_to_hash() {
+        let directory = std::env::temp_dir().join(format!("danmaku-small-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let file = |name: &str| {
+            let path = directory.join(name);
+            std::fs::write(&path, b"").unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let mut linkage = Linkage::new();
+        let path = file("[Group] Frieren - 05.mkv");
+        assert!(linkage.file_hash(&path).is_err());
+
+        linkage.link_file(&path, 123450005);
+        assert!(linkage.files.is_empty());
+        let next = file("[Group] Frieren - 06.mkv");
+        assert_eq!(linkage.get_directories(&next), Some(123450006));
+
+        assert!(linkage.forget_file(&path));
+        assert_eq!(linkage.get_directories(&next), None);
+        assert!(!linkage.forget_file(&path));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}